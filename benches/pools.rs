@@ -0,0 +1,141 @@
+// Throughput of ThreadPool, whose workers share one queue behind a mutex, against
+// StealingPool, whose workers have a queue each, on many small jobs.
+//
+//     cargo bench --bench pools [-- THREADS]
+//
+// "outside" queues every job from the main thread. "nested" queues a few jobs that each
+// queue a share of the tiny ones, which is where the per-worker queues should pay off.
+// "uneven" is nested too, but the jobs of every eighth root take a lot longer, so the
+// workers that got those have to be helped out.
+use rust_book::{StealingPool, ThreadPool};
+use std::hint::black_box;
+use std::sync::atomic::{AtomicUsize, Ordering};
+use std::sync::Arc;
+use std::thread;
+use std::time::{Duration, Instant};
+
+const JOBS: usize = 200_000;
+const ROOTS: usize = 64;
+const RUNS: usize = 5;
+// how long the long jobs of "uneven" take, in rounds of `spin`
+const LONG: u64 = 2_000;
+
+// What the two pools have in common, for the benchmark.
+trait Pool: Send + Sync + 'static {
+    fn start(threads: usize) -> Self;
+    fn run(&self, job: impl FnOnce() + Send + 'static);
+}
+
+impl Pool for ThreadPool {
+    fn start(threads: usize) -> Self {
+        ThreadPool::quiet(threads)
+    }
+
+    fn run(&self, job: impl FnOnce() + Send + 'static) {
+        self.execute(job);
+    }
+}
+
+impl Pool for StealingPool {
+    fn start(threads: usize) -> Self {
+        StealingPool::new(threads)
+    }
+
+    fn run(&self, job: impl FnOnce() + Send + 'static) {
+        self.execute(job);
+    }
+}
+
+fn outside<P: Pool>(pool: &Arc<P>, left: &Arc<AtomicUsize>) {
+    for _ in 0..JOBS {
+        let left = Arc::clone(left);
+        pool.run(move || {
+            left.fetch_sub(1, Ordering::SeqCst);
+        });
+    }
+}
+
+fn nested<P: Pool>(pool: &Arc<P>, left: &Arc<AtomicUsize>) {
+    for _ in 0..ROOTS {
+        let (inner, left) = (Arc::clone(pool), Arc::clone(left));
+        pool.run(move || {
+            for _ in 0..JOBS / ROOTS {
+                let left = Arc::clone(&left);
+                inner.run(move || {
+                    left.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+    }
+}
+
+fn uneven<P: Pool>(pool: &Arc<P>, left: &Arc<AtomicUsize>) {
+    for root in 0..ROOTS {
+        let (inner, left) = (Arc::clone(pool), Arc::clone(left));
+        let rounds = if root % 8 == 0 { LONG } else { 1 };
+        pool.run(move || {
+            for _ in 0..JOBS / ROOTS {
+                let left = Arc::clone(&left);
+                inner.run(move || {
+                    spin(rounds);
+                    left.fetch_sub(1, Ordering::SeqCst);
+                });
+            }
+        });
+    }
+}
+
+// Work the optimizer can't skip.
+fn spin(rounds: u64) -> u64 {
+    (0..rounds).fold(0, |sum, i| black_box(sum ^ i))
+}
+
+// The best of a few runs, in jobs per second.
+fn measure<P: Pool>(threads: usize, queue: fn(&Arc<P>, &Arc<AtomicUsize>)) -> f64 {
+    let mut best = Duration::MAX;
+    for _ in 0..RUNS {
+        let pool = Arc::new(P::start(threads));
+        let left = Arc::new(AtomicUsize::new(JOBS / ROOTS * ROOTS));
+        let start = Instant::now();
+        queue(&pool, &left);
+        while left.load(Ordering::SeqCst) > 0 {
+            thread::yield_now();
+        }
+        best = best.min(start.elapsed());
+        // the jobs let go of their clones, so dropping the pool doesn't happen on a worker
+        while Arc::strong_count(&pool) > 1 {
+            thread::yield_now();
+        }
+    }
+    (JOBS / ROOTS * ROOTS) as f64 / best.as_secs_f64()
+}
+
+fn main() {
+    // `cargo bench --bench pools -- 8` for 8 threads
+    let threads = std::env::args()
+        .find_map(|arg| arg.parse().ok())
+        .unwrap_or_else(|| thread::available_parallelism().map_or(4, |n| n.get()));
+    println!(
+        "{} small jobs on {} threads, best of {}",
+        JOBS, threads, RUNS
+    );
+    println!("{:<10} {:>16} {:>16}", "", "ThreadPool", "StealingPool");
+    println!(
+        "{:<10} {:>11.0} jobs/s {:>11.0} jobs/s",
+        "outside",
+        measure::<ThreadPool>(threads, outside),
+        measure::<StealingPool>(threads, outside)
+    );
+    println!(
+        "{:<10} {:>11.0} jobs/s {:>11.0} jobs/s",
+        "nested",
+        measure::<ThreadPool>(threads, nested),
+        measure::<StealingPool>(threads, nested)
+    );
+    println!(
+        "{:<10} {:>11.0} jobs/s {:>11.0} jobs/s",
+        "uneven",
+        measure::<ThreadPool>(threads, uneven),
+        measure::<StealingPool>(threads, uneven)
+    );
+}
@@ -20,7 +20,7 @@
 */
 
 // since we moved the code to lib.rs we need to import it again
-use rust_book::{execute, Config};
+use rust_book::{execute, Config, MinigrepError};
 
 #[allow(dead_code)]
 
@@ -35,12 +35,45 @@ pub fn run() {
 // we need to accept cmd-line arguments
 // like 'cargo run searchstring file.txt'
 fn mini_main() {
-    let config = Config::new(std::env::args()).unwrap_or_else(|err| {
+    let config = Config::from_env(std::env::args()).unwrap_or_else(|err| {
         eprintln!("Problem parsing arguments: {}", err);
-        std::process::exit(1);
+        eprintln!("Try '--help' for more information.");
+        std::process::exit(exit_code(&err));
     });
+    for warning in config.warnings() {
+        eprintln!("minigrep: {}", warning);
+    }
     if let Err(e) = execute(config) {
         eprintln!("Application error {}", e);
-        std::process::exit(1);
+        // the error might have been caused by another one, e.g. the regex parser
+        let mut source = std::error::Error::source(&e);
+        while let Some(cause) = source {
+            eprintln!("  caused by: {}", cause);
+            source = cause.source();
+        }
+        std::process::exit(exit_code(&e));
     };
 }
+
+// every kind of error gets its own exit code, so scripts can tell them apart. An error caused
+// by another one of ours exits like its cause, so a run that went on after a file failed exits
+// like that first failure
+fn exit_code(error: &MinigrepError) -> i32 {
+    let cause = std::error::Error::source(error).and_then(|e| e.downcast_ref::<MinigrepError>());
+    if let Some(cause) = cause {
+        return exit_code(cause);
+    }
+    match error {
+        MinigrepError::MissingQuery => 2,
+        MinigrepError::Usage(_) => 3,
+        MinigrepError::InvalidPattern(_) => 4,
+        MinigrepError::NotFound { .. } => 5,
+        MinigrepError::InvalidUtf8 { .. } => 6,
+        MinigrepError::Io { .. } => 7,
+        MinigrepError::Walk(_) => 8,
+        MinigrepError::Output(_) => 9,
+        MinigrepError::Settings { .. } => 10,
+        // always has a cause
+        MinigrepError::Incomplete { .. } => 1,
+    }
+}
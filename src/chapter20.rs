@@ -11,7 +11,7 @@ pub fn run() {
 * -> Create proper HTTP response
 * -> Improve the throughput of pour server with a thread pool
 */
-use rust_book::ThreadPool;
+use rust_book::{Overflow, PoolOptions, ThreadPool};
 use std::io::prelude::*;
 use std::net::TcpListener;
 use std::net::TcpStream;
@@ -19,7 +19,14 @@ use std::time::Duration;
 
 fn web_server_main() {
     let listener = TcpListener::bind("127.0.0.1:7878").unwrap();
-    let pool = ThreadPool::new(4);
+    // a bounded queue, so a flood of connections waits in the OS backlog instead of memory
+    let pool = ThreadPool::with_options(PoolOptions {
+        threads: 4,
+        capacity: Some(64),
+        overflow: Overflow::Block,
+        verbose: true,
+        ..PoolOptions::default()
+    });
 
     for stream in listener.incoming() {
         let stream = stream.unwrap();
@@ -17,67 +17,14 @@ pub fn add_one(x: i32) -> i32 {
     x + 1
 }
 
-// return unit value or some
-// object that implement the error trait
-pub fn execute(config: Config) -> Result<(), Box<dyn std::error::Error>> {
-    let contents = std::fs::read_to_string(config.filename)?; // since we return a
-                                                              // Result we move the error just up
-    let results = if config.case_sensitive {
-        search(&config.query, &contents)
-    } else {
-        search_case_insensitive(&config.query, &contents)
-    };
-    for line in results {
-        println!("{}", line);
-    }
-    Ok(())
-}
-
-pub struct Config {
-    query: String,
-    filename: String,
-    case_sensitive: bool,
-}
-
-impl Config {
-    pub fn new(mut args: std::env::Args) -> Result<Config, &'static str> {
-        args.next();
-        // println!("{:?}", args); // first argument is the path of the binary
-        let query = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a query string"),
-        };
-        let filename = match args.next() {
-            Some(arg) => arg,
-            None => return Err("Didn't get a file name"),
-        };
-        let case_sensitive = std::env::var("CASE_INSENSITVE").is_err();
-        Ok(Config {
-            query,
-            filename,
-            case_sensitive,
-        })
-    }
-}
-
-pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    let mut results = Vec::new();
-    let query = query.to_lowercase();
-    for line in contents.lines() {
-        if line.to_lowercase().contains(&query) {
-            results.push(line.trim());
-        }
-    }
-    results
-}
+//////////////////////////// Chapter 12: minigrep ////////////////////////////////////
+mod minigrep;
 
-pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
-    contents
-        .lines()
-        .filter(|s| s.contains(query))
-        .map(|s| s.trim())
-        .collect()
-}
+pub use minigrep::{
+    execute, search, search_case_insensitive, search_lines, search_pattern, usage, BinaryMode,
+    ColorChoice, Config, Encoding, Fold, Index, JsonPrinter, Match, Matches, MinigrepError,
+    Normalization, OutputMode, Pattern, PrintOptions, Printer, Searcher, Sink, WalkOptions,
+};
 
 #[cfg(test)]
 mod tests {
@@ -107,6 +54,34 @@ mod tests {
         );
     }
 
+    #[test]
+    fn line_numbers() {
+        let pattern = Pattern::literal("us", true);
+        let contents = "\
+I'm nobody! Who are you?
+Then there's a pair of us — don't tell!
+They'd banish us, you know.";
+        assert_eq!(
+            vec![
+                Match {
+                    line_number: 2,
+                    offset: 25,
+                    line: "Then there's a pair of us — don't tell!",
+                    untrimmed: "Then there's a pair of us — don't tell!",
+                    spans: vec![23..25],
+                },
+                Match {
+                    line_number: 3,
+                    offset: 67,
+                    line: "They'd banish us, you know.",
+                    untrimmed: "They'd banish us, you know.",
+                    spans: vec![14..16],
+                },
+            ],
+            search_lines(&pattern, contents)
+        );
+    }
+
     //////////////////////////// Chapter 15: Mock Object ////////////////////////////////////
     use std::cell::RefCell;
     struct MockMessenger {
@@ -159,7 +134,7 @@ impl<'a, T> LimitTracker<'a, T>
 where
     T: Messenger,
 {
-    pub fn new(messenger: &T, max: usize) -> LimitTracker<T> {
+    pub fn new(messenger: &T, max: usize) -> LimitTracker<'_, T> {
         LimitTracker {
             messenger,
             value: 0,
@@ -183,93 +158,11 @@ where
 }
 
 //////////////////////////// Chapter 20: Multithreaded Server ////////////////////////////////////
-use std::sync::{mpsc, Arc, Mutex};
-
-pub struct ThreadPool {
-    workers: Vec<Worker>,
-    sender: mpsc::Sender<Message>,
-}
-
-trait FnBox {
-    fn call_box(self: Box<Self>);
-}
-
-impl<F: FnOnce()> FnBox for F {
-    fn call_box(self: Box<Self>) {
-        (*self)();
-    }
-}
-
-type Job = Box<dyn FnBox + Send + 'static>;
-
-impl ThreadPool {
-    /// Create a new ThreadPool
-    ///
-    /// # Penics
-    ///
-    /// The `new` function will panic!("c if n_thread is 0");
-    pub fn new(number_of_threads: usize) -> ThreadPool {
-        assert!(number_of_threads > 0);
-        let (sender, receiver) = mpsc::channel();
-        let receiver = Arc::new(Mutex::new(receiver));
-        let mut workers = Vec::with_capacity(number_of_threads);
-        for id in 0..number_of_threads {
-            workers.push(Worker::new(id, Arc::clone(&receiver)));
-        }
-        ThreadPool { workers, sender }
-    }
-
-    pub fn execute<F>(&self, f: F)
-    where
-        F: FnOnce() + Send + 'static,
-    {
-        let job = Box::new(f);
-        self.sender.send(Message::NewJob(job)).unwrap();
-    }
-}
-
-impl Drop for ThreadPool {
-    fn drop(&mut self) {
-        println!("Sending Terminate!");
-        // we need this additional for loop to guarantuee that every worker got ther terminate match message when we call join
-        for _ in &mut self.workers {
-            self.sender.send(Message::Terminate).unwrap();
-        }
-
-        for worker in &mut self.workers {
-            println!("Shutting down Worker {}", worker.id);
-            if let Some(thread) = worker.thread.take() {
-                thread.join().unwrap();
-            }
-        }
-    }
-}
-
-struct Worker {
-    id: usize,
-    thread: Option<std::thread::JoinHandle<()>>,
-}
-
-impl Worker {
-    fn new(id: usize, receiver: Arc<Mutex<mpsc::Receiver<Message>>>) -> Worker {
-        let thread = Some(std::thread::spawn(move || loop {
-            let message = receiver.lock().unwrap().recv().unwrap();
-            match message {
-                Message::NewJob(job) => {
-                    println!("Worker {} got job; executing.", id);
-                    job.call_box();
-                }
-                Message::Terminate => {
-                    println!("Worker {} terminating...", id);
-                    break;
-                }
-            }
-        }));
-        Worker { id, thread }
-    }
-}
-
-enum Message {
-    NewJob(Job),
-    Terminate,
-}
+mod stealing_pool;
+mod thread_pool;
+
+pub use stealing_pool::StealingPool;
+pub use thread_pool::{
+    JobCounts, JobError, JobHandle, Overflow, PoolOptions, ShutdownMode, ShutdownReport,
+    ThreadPool, WorkerReport,
+};
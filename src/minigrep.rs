@@ -0,0 +1,840 @@
+mod config;
+mod decode;
+mod error;
+mod fold;
+mod follow;
+mod fuzzy;
+mod index;
+mod json;
+mod pattern;
+mod printer;
+mod replace;
+mod searcher;
+mod settings;
+mod walk;
+
+use crate::ThreadPool;
+use config::Action;
+use decode::{Decoded, LineReader};
+use std::collections::VecDeque;
+use std::io::{self, BufRead, BufReader, IsTerminal, Read, Write};
+use std::path::{Path, PathBuf};
+use std::sync::{mpsc, Arc};
+use std::time::{Duration, Instant};
+
+pub use config::{usage, Config};
+pub use decode::{BinaryMode, Encoding};
+pub use error::MinigrepError;
+pub use fold::{Fold, Normalization};
+pub use index::Index;
+pub use json::JsonPrinter;
+pub use pattern::Pattern;
+pub use printer::{ColorChoice, OutputMode, PrintOptions, Printer, Sink};
+pub use searcher::{Match, Matches, Searcher};
+pub use walk::WalkOptions;
+
+// return unit value or a MinigrepError that tells the caller what went wrong
+pub fn execute(config: Config) -> Result<(), MinigrepError> {
+    let started = Instant::now();
+    let mut print = config.print;
+    print.color = match config.color {
+        ColorChoice::Always => true,
+        ColorChoice::Never => false,
+        // no colors if we are piped into another program or a file
+        ColorChoice::Auto => io::stdout().is_terminal(),
+    };
+    match config.action {
+        Action::Help => {
+            print!("{}", usage());
+            return io::stdout().flush().map_err(MinigrepError::Output);
+        }
+        Action::Version => {
+            println!("minigrep {}", env!("CARGO_PKG_VERSION"));
+            return io::stdout().flush().map_err(MinigrepError::Output);
+        }
+        Action::Index => {
+            let root = Path::new(&config.paths[0]);
+            let index = Index::build(root, &config.walk)?;
+            index.save()?;
+            println!(
+                "indexed {} words in {} files into {}",
+                index.terms(),
+                index.files(),
+                root.join(index::INDEX_FILE).display()
+            );
+            return io::stdout().flush().map_err(MinigrepError::Output);
+        }
+        Action::Query => return query(&config, print),
+        Action::PrintConfig => {
+            print!("{}", config.describe());
+            return io::stdout().flush().map_err(MinigrepError::Output);
+        }
+        Action::Search => {}
+    }
+    let search = Search {
+        pattern: config.pattern()?,
+        invert: config.invert,
+        mode: config.print.mode,
+        replace: config.replace.clone(),
+        encoding: config.encoding,
+        binary: config.binary,
+    };
+    if config.follow {
+        return follow(Path::new(&config.paths[0]), &search, print);
+    }
+    let mut failures = Failures::default();
+    let mut files = Vec::new();
+    for path in &config.paths {
+        let found = walk::files(Path::new(path), &config.walk)?;
+        for e in found.errors {
+            failures.report(MinigrepError::Walk(e));
+        }
+        files.extend(found.files);
+    }
+    if files.is_empty() && config.paths.is_empty() {
+        files.push(Path::new(STDIN).to_path_buf());
+    }
+    // a single file is printed like before, with more files we need to know where a line is from
+    print.show_paths = files.len() > 1 || config.paths.iter().any(|p| Path::new(p).is_dir());
+
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    if config.dry_run || config.in_place {
+        rewrite(&files, &search, config.dry_run, &mut out, &mut failures)?;
+        return failures.result();
+    }
+    let stats = if files.len() > 1 {
+        search_parallel(files, search, print, &mut out, &mut failures)?
+    } else {
+        let mut stats = Stats::default();
+        let mut sink = printer::sink(&mut out, print);
+        for file in files {
+            sink.start_file(&display_name(&file))
+                .map_err(MinigrepError::Output)?;
+            match search.file(&file, sink.as_mut()) {
+                Ok(file_stats) => stats.add(&file_stats),
+                Err(MinigrepError::Output(e)) => return Err(MinigrepError::Output(e)),
+                // one unreadable file should not stop us from searching the rest of the tree
+                Err(e) if print.show_paths => failures.report(e),
+                Err(e) => return Err(e), // since we return a Result we move the error just up
+            }
+        }
+        sink.flush().map_err(MinigrepError::Output)?;
+        stats
+    };
+    if print.json {
+        json::summary(&mut out, &stats, started.elapsed()).map_err(MinigrepError::Output)?;
+    }
+    out.flush().map_err(MinigrepError::Output)?;
+    failures.result()
+}
+
+/// The errors we printed and then carried on, so the run still ends up failing.
+#[derive(Debug, Default)]
+pub(crate) struct Failures {
+    failed: usize,
+    first: Option<MinigrepError>,
+}
+
+impl Failures {
+    fn report(&mut self, error: MinigrepError) {
+        eprintln!("minigrep: {}", error);
+        self.failed += 1;
+        self.first.get_or_insert(error);
+    }
+
+    fn result(self) -> Result<(), MinigrepError> {
+        match self.first {
+            Some(first) => Err(MinigrepError::Incomplete {
+                failed: self.failed,
+                first: Box::new(first),
+            }),
+            None => Ok(()),
+        }
+    }
+}
+
+/// What we found while searching.
+#[derive(Debug, Default, Clone, Copy, PartialEq)]
+pub(crate) struct Stats {
+    pub(crate) files: usize,
+    pub(crate) files_with_matches: usize,
+    pub(crate) matched_lines: usize,
+    pub(crate) matches: usize,
+}
+
+impl Stats {
+    fn add(&mut self, other: &Stats) {
+        self.files += other.files;
+        self.files_with_matches += other.files_with_matches;
+        self.matched_lines += other.matched_lines;
+        self.matches += other.matches;
+    }
+}
+
+/// Search every file in its own job on a ThreadPool.
+///
+/// Each job prints into a buffer. The buffers are written to `out` in the order of `files`,
+/// as soon as all the files before them are done, so the output doesn't depend on which
+/// worker finishes first.
+fn search_parallel<W: Write>(
+    files: Vec<PathBuf>,
+    search: Search,
+    print: PrintOptions,
+    out: &mut W,
+    failures: &mut Failures,
+) -> Result<Stats, MinigrepError> {
+    let threads = std::thread::available_parallelism().map_or(4, |n| n.get());
+    let pool = ThreadPool::quiet(threads.min(files.len()));
+    let search = Arc::new(search);
+    let (sender, receiver) = mpsc::channel();
+    let total = files.len();
+    for (i, file) in files.into_iter().enumerate() {
+        let search = Arc::clone(&search);
+        let sender = sender.clone();
+        pool.execute(move || {
+            let name = display_name(&file);
+            let mut buffer = Vec::new();
+            let mut sink = printer::sink(&mut buffer, print);
+            let result = match sink.start_file(&name) {
+                Ok(()) => search.file(&file, sink.as_mut()),
+                Err(e) => Err(MinigrepError::Output(e)),
+            };
+            drop(sink);
+            // the receiver only hangs up if writing to stdout failed, nothing left to do then
+            let _ = sender.send((i, result.map(|stats| (buffer, stats))));
+        });
+    }
+    drop(sender);
+
+    let mut stats = Stats::default();
+    let mut printed_any = false;
+    let mut done: Vec<Option<_>> = (0..total).map(|_| None).collect();
+    let mut next = 0;
+    for (i, result) in receiver {
+        done[i] = Some(result);
+        while let Some(Some(result)) = done.get_mut(next).map(Option::take) {
+            next += 1;
+            match result {
+                Ok((buffer, file_stats)) => {
+                    stats.add(&file_stats);
+                    // every file has its own printer, so we separate the files here
+                    if file_stats.matched_lines > 0 && printed_any {
+                        write!(out, "{}", printer::separator(&print))
+                            .map_err(MinigrepError::Output)?;
+                    }
+                    out.write_all(&buffer).map_err(MinigrepError::Output)?;
+                    printed_any |= file_stats.matched_lines > 0;
+                }
+                Err(e) => failures.report(e),
+            }
+        }
+    }
+    Ok(stats)
+}
+
+/// Apply the replacement to whole files, either printing a diff or writing them back.
+fn rewrite<W: Write>(
+    files: &[PathBuf],
+    search: &Search,
+    dry_run: bool,
+    out: &mut W,
+    failures: &mut Failures,
+) -> Result<(), MinigrepError> {
+    let replacement = search.replace.as_deref().unwrap_or_default();
+    for file in files {
+        let bytes = if file == Path::new(STDIN) {
+            if !dry_run {
+                return Err(MinigrepError::Usage(String::from(
+                    "'--in-place' needs files, it can't rewrite standard input",
+                )));
+            }
+            let mut bytes = Vec::new();
+            io::stdin().read_to_end(&mut bytes).map(|_| bytes)
+        } else {
+            std::fs::read(file)
+        };
+        let bytes = match bytes {
+            Ok(bytes) => bytes,
+            Err(e) => {
+                failures.report(MinigrepError::read(file, e));
+                continue;
+            }
+        };
+        // a file we can't turn into text and back exactly is left as it is
+        let decoded = match Decoded::new(&bytes, search.encoding, search.binary) {
+            Ok(decoded) => decoded,
+            Err(why) => {
+                eprintln!("minigrep: {}: skipped, {}", display_name(file), why);
+                continue;
+            }
+        };
+        let rewrite = replace::Rewrite::new(&search.pattern, &decoded.text, replacement);
+        let Some(contents) = decoded.encode(&rewrite.contents()) else {
+            eprintln!(
+                "minigrep: {}: skipped, the replacement doesn't fit in its encoding",
+                display_name(file)
+            );
+            continue;
+        };
+        if dry_run {
+            out.write_all(rewrite.diff(&display_name(file)).as_bytes())
+                .map_err(MinigrepError::Output)?;
+        } else if rewrite.is_changed() {
+            if let Err(e) = replace::write_atomically(file, &contents) {
+                failures.report(e);
+            }
+        }
+    }
+    out.flush().map_err(MinigrepError::Output)
+}
+
+// how often --follow looks for new lines
+const POLL_INTERVAL: Duration = Duration::from_millis(250);
+
+/// Print the matching lines of `path` and then keep waiting for more, until we get killed.
+fn follow(path: &Path, search: &Search, print: PrintOptions) -> Result<(), MinigrepError> {
+    if path.is_dir() {
+        return Err(MinigrepError::Usage(String::from(
+            "'--follow' needs a file, not a directory",
+        )));
+    }
+    let mut follower = follow::Follower::open(path, search.encoding)?;
+    let stdout = io::stdout();
+    let mut sink = printer::sink(stdout.lock(), print);
+    sink.start_file(&display_name(path))
+        .map_err(MinigrepError::Output)?;
+    loop {
+        follower.poll(search, sink.as_mut())?;
+        // the lines are no use if they only show up once the buffer is full
+        sink.flush().map_err(MinigrepError::Output)?;
+        std::thread::sleep(POLL_INTERVAL);
+    }
+}
+
+/// Look the query up in the index of the directory, rebuilding the index if it is out of
+/// date, and print the lines it finds like a search would.
+fn query(config: &Config, mut print: PrintOptions) -> Result<(), MinigrepError> {
+    let index = Index::open(Path::new(&config.paths[0]), &config.walk)?;
+    let hits = index.query(&config.query);
+    print.show_paths = true;
+    let stdout = io::stdout();
+    let mut out = stdout.lock();
+    let mut sink = printer::sink(&mut out, print);
+    let mut i = 0;
+    while i < hits.len() {
+        let path = &hits[i].0;
+        let lines = hits[i..].iter().take_while(|(p, _)| p == path).count();
+        sink.start_file(&display_name(path))
+            .map_err(MinigrepError::Output)?;
+        // the index only knows the line numbers, the text comes from the file
+        let file = std::fs::File::open(path).map_err(|e| MinigrepError::read(path, e))?;
+        let mut reader = LineReader::new(BufReader::new(file), config.encoding);
+        let mut wanted = hits[i..i + lines].iter().map(|(_, line)| *line).peekable();
+        let (mut line, mut line_number) = (String::new(), 0);
+        // counts and file lists don't need the lines
+        while print.mode == OutputMode::Lines && wanted.peek().is_some() {
+            let read = reader
+                .read_line(&mut line)
+                .map_err(|e| MinigrepError::read(path, e))?;
+            if read == 0 {
+                break;
+            }
+            line_number += 1;
+            if wanted.next_if_eq(&line_number).is_some() {
+                let text = line.trim_end_matches(['\n', '\r']);
+                let spans = index::highlight(&config.query, text);
+                sink.line(line_number, reader.line_offset(), text, Some(&spans))
+                    .map_err(MinigrepError::Output)?;
+            }
+        }
+        sink.end_file(lines).map_err(MinigrepError::Output)?;
+        i += lines;
+    }
+    sink.flush().map_err(MinigrepError::Output)?;
+    drop(sink);
+    out.flush().map_err(MinigrepError::Output)
+}
+
+fn display_name(file: &Path) -> String {
+    if file == Path::new(STDIN) {
+        String::from("(standard input)")
+    } else {
+        file.display().to_string()
+    }
+}
+
+// '-' as a file name means we read from stdin, just like grep does
+const STDIN: &str = "-";
+
+// how much of a file we look at for NUL bytes before printing any of it, when binary files
+// are to be skipped
+const BINARY_WINDOW: usize = 64 * 1024;
+
+/// How every file gets searched.
+pub(crate) struct Search {
+    pub(crate) pattern: Pattern,
+    /// Select the lines that don't match.
+    pub(crate) invert: bool,
+    pub(crate) mode: OutputMode,
+    /// Print the lines with every hit replaced by this.
+    pub(crate) replace: Option<String>,
+    pub(crate) encoding: Encoding,
+    pub(crate) binary: BinaryMode,
+}
+
+impl Search {
+    fn file<S: Sink + ?Sized>(&self, path: &Path, sink: &mut S) -> Result<Stats, MinigrepError> {
+        if path == Path::new(STDIN) {
+            self.reader(io::stdin().lock(), Path::new("(standard input)"), sink)
+        } else {
+            let file = std::fs::File::open(path).map_err(|e| MinigrepError::read(path, e))?;
+            self.reader(BufReader::new(file), path, sink)
+        }
+    }
+
+    // A binary file in skip mode counts as searched, without matches.
+    fn skipped<S: Sink + ?Sized>(&self, sink: &mut S) -> Result<Stats, MinigrepError> {
+        sink.end_file(0).map_err(MinigrepError::Output)?;
+        Ok(Stats {
+            files: 1,
+            ..Stats::default()
+        })
+    }
+
+    /// Search the input line by line, so we only ever hold one line (plus the context lines)
+    /// in memory no matter how big the input is. `path` is only used for error messages.
+    ///
+    /// To skip binary files, the lines of the first `BINARY_WINDOW` bytes are held back until
+    /// we know there is no NUL byte among them. A NUL byte after that stops the search, the
+    /// lines before it have been printed already.
+    fn reader<R: BufRead, S: Sink + ?Sized>(
+        &self,
+        reader: R,
+        path: &Path,
+        sink: &mut S,
+    ) -> Result<Stats, MinigrepError> {
+        let mut stats = Stats {
+            files: 1,
+            ..Stats::default()
+        };
+        let mut lines = LineReader::new(reader, self.encoding);
+        let encoding = lines.encoding().map_err(|e| MinigrepError::read(path, e))?;
+        sink.decoded_from(encoding);
+        let mut line = String::new();
+        let mut read_line = |line: &mut String| {
+            let read = lines
+                .read_line(line)
+                .map_err(|e| MinigrepError::read(path, e))?;
+            let binary = lines.is_binary() && self.binary != BinaryMode::Text;
+            Ok::<_, MinigrepError>((read, lines.line_offset(), binary))
+        };
+        let mut held = VecDeque::new();
+        if self.binary == BinaryMode::Skip {
+            let mut window = 0;
+            while window < BINARY_WINDOW {
+                let (read, offset, binary) = read_line(&mut line)?;
+                if binary {
+                    return self.skipped(sink);
+                }
+                if read == 0 {
+                    break;
+                }
+                window += read;
+                held.push_back((std::mem::take(&mut line), offset));
+            }
+        }
+        let mut line_number = 0;
+        loop {
+            let (offset, binary) = match held.pop_front() {
+                Some((held_line, offset)) => {
+                    line = held_line;
+                    (offset, false)
+                }
+                None => match read_line(&mut line)? {
+                    (0, _, _) => break,
+                    (_, offset, binary) => (offset, binary),
+                },
+            };
+            if binary && self.binary == BinaryMode::Skip {
+                break;
+            }
+            line_number += 1;
+            let text = line.trim_end_matches(['\n', '\r']);
+            let selected = self.pattern.is_match(text) != self.invert;
+            if selected {
+                stats.matched_lines += 1;
+            }
+            let printed = match self.mode {
+                // the lines of a binary file are no use on a terminal, one note is enough
+                OutputMode::Lines if selected && binary => {
+                    sink.binary_match().map_err(MinigrepError::Output)?;
+                    break;
+                }
+                // the first match already tells whether the file gets listed or not
+                OutputMode::FilesWithMatches | OutputMode::FilesWithoutMatch if selected => break,
+                OutputMode::Lines => self.line(line_number, offset, text, selected, sink),
+                _ => Ok(0),
+            };
+            stats.matches += printed.map_err(MinigrepError::Output)?;
+        }
+        if stats.matched_lines > 0 {
+            stats.files_with_matches = 1;
+        }
+        sink.end_file(stats.matched_lines)
+            .map_err(MinigrepError::Output)?;
+        Ok(stats)
+    }
+
+    /// Hand a line to the sink, with its hits if it is `selected`. Returns how many hits
+    /// there were.
+    fn line<S: Sink + ?Sized>(
+        &self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        selected: bool,
+        sink: &mut S,
+    ) -> io::Result<usize> {
+        if !selected {
+            return sink.line(line_number, offset, text, None).map(|()| 0);
+        }
+        if self.invert {
+            return sink.line(line_number, offset, text, Some(&[])).map(|()| 0);
+        }
+        if let Some(replacement) = &self.replace {
+            let (replaced, spans) = self.pattern.replace(text, replacement);
+            sink.line(line_number, offset, &replaced, Some(&spans))?;
+            return Ok(spans.len());
+        }
+        if let Pattern::Fuzzy { .. } = self.pattern {
+            let hits = self.pattern.find_distances(text);
+            sink.fuzzy_line(line_number, offset, text, &hits)?;
+            return Ok(hits.len());
+        }
+        // most lines don't match, so we only look for the exact spans once we know there
+        // are some
+        if self.pattern.patterns() > 1 {
+            let hits = self.pattern.find_patterns(text);
+            sink.multi_line(line_number, offset, text, &hits)?;
+            return Ok(hits.len());
+        }
+        let spans = self.pattern.find_spans(text);
+        sink.line(line_number, offset, text, Some(&spans))?;
+        Ok(spans.len())
+    }
+}
+
+pub fn search_case_insensitive<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    search_pattern(&Pattern::literal(query, false), contents)
+}
+
+pub fn search<'a>(query: &str, contents: &'a str) -> Vec<&'a str> {
+    search_pattern(&Pattern::literal(query, true), contents)
+}
+
+/// Like [`search_pattern`], but keeps the line numbers and positions around.
+pub fn search_lines<'a>(pattern: &Pattern, contents: &'a str) -> Vec<Match<'a>> {
+    Matches::new(pattern, contents).collect()
+}
+
+/// Return every (trimmed) line of `contents` that matches `pattern`.
+pub fn search_pattern<'a>(pattern: &Pattern, contents: &'a str) -> Vec<&'a str> {
+    contents
+        .lines()
+        .filter(|s| pattern.is_match(s))
+        .map(|s| s.trim())
+        .collect()
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // the search the tests start from, it prints the lines that match `pattern`
+    pub(super) fn search(pattern: Pattern) -> Search {
+        Search {
+            pattern,
+            invert: false,
+            mode: OutputMode::Lines,
+            replace: None,
+            encoding: Encoding::Auto,
+            binary: BinaryMode::Quiet,
+        }
+    }
+
+    #[test]
+    fn parallel_output_keeps_file_order() {
+        let dir = tempfile::tempdir().unwrap();
+        let mut files = Vec::new();
+        let mut expected = String::new();
+        for i in 0..20 {
+            let file = dir.path().join(format!("{:02}.txt", i));
+            // make the early files the slow ones
+            let filler = "nothing to see here\n".repeat((20 - i) * 500);
+            std::fs::write(&file, format!("{}frog {}\n", filler, i)).unwrap();
+            expected.push_str(&format!("{}:frog {}\n", file.display(), i));
+            files.push(file);
+        }
+
+        let search = search(Pattern::literal("frog", true));
+        let print = PrintOptions {
+            show_paths: true,
+            ..PrintOptions::default()
+        };
+        let mut out = Vec::new();
+        let mut failures = Failures::default();
+        let stats = search_parallel(files, search, print, &mut out, &mut failures).unwrap();
+        assert!(failures.result().is_ok());
+        assert_eq!(
+            Stats {
+                files: 20,
+                files_with_matches: 20,
+                matched_lines: 20,
+                matches: 20
+            },
+            stats
+        );
+        assert_eq!(expected, String::from_utf8(out).unwrap());
+    }
+
+    #[test]
+    fn missing_files_fail_the_run() {
+        let dir = tempfile::tempdir().unwrap();
+        let missing = dir.path().join("missing.txt");
+        let ok = dir.path().join("ok.txt");
+        std::fs::write(&ok, "a frog\n").unwrap();
+        let search = search(Pattern::literal("frog", true));
+        let print = PrintOptions {
+            show_paths: true,
+            ..PrintOptions::default()
+        };
+        let mut out = Vec::new();
+        let mut failures = Failures::default();
+        let stats = search_parallel(
+            vec![missing, ok.clone()],
+            search,
+            print,
+            &mut out,
+            &mut failures,
+        )
+        .unwrap();
+        // the readable file still got searched
+        assert_eq!(1, stats.matched_lines);
+        assert_eq!(
+            format!("{}:a frog\n", ok.display()),
+            String::from_utf8(out).unwrap()
+        );
+        match failures.result() {
+            Err(MinigrepError::Incomplete { failed: 1, first }) => {
+                assert!(matches!(*first, MinigrepError::NotFound { .. }))
+            }
+            other => panic!("expected the run to fail, got {:?}", other),
+        }
+    }
+
+    #[test]
+    fn rewrite_keeps_going_and_keeps_the_encoding() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = |name: &str| dir.path().join(name);
+        std::fs::write(path("latin1.txt"), b"un caf\xe9 frog\n").unwrap();
+        std::fs::write(path("binary.bin"), b"\x7fELF\0 frog\n").unwrap();
+        std::fs::write(path("broken.txt"), b"caf\xe9 frog\n").unwrap();
+        std::fs::write(path("ok.txt"), "a frog\n").unwrap();
+        let files = [
+            "missing.txt",
+            "latin1.txt",
+            "binary.bin",
+            "broken.txt",
+            "ok.txt",
+        ];
+        let files: Vec<_> = files.iter().map(|name| path(name)).collect();
+        let mut search = Search {
+            replace: Some(String::from("toad")),
+            ..search(Pattern::literal("frog", true))
+        };
+        let mut failures = Failures::default();
+        rewrite(&files, &search, false, &mut Vec::new(), &mut failures).unwrap();
+        // only the missing file fails the run, the others are skipped or rewritten
+        assert!(matches!(
+            failures.result(),
+            Err(MinigrepError::Incomplete { failed: 1, .. })
+        ));
+        assert_eq!(b"a toad\n", &std::fs::read(path("ok.txt")).unwrap()[..]);
+        assert_eq!(
+            b"caf\xe9 frog\n",
+            &std::fs::read(path("broken.txt")).unwrap()[..]
+        );
+        assert_eq!(
+            b"\x7fELF\0 frog\n",
+            &std::fs::read(path("binary.bin")).unwrap()[..]
+        );
+        // Latin-1 has to be asked for, and then it's written back as Latin-1
+        assert_eq!(
+            b"un caf\xe9 frog\n",
+            &std::fs::read(path("latin1.txt")).unwrap()[..]
+        );
+        search.encoding = Encoding::Latin1;
+        let mut failures = Failures::default();
+        rewrite(&files[1..2], &search, false, &mut Vec::new(), &mut failures).unwrap();
+        assert!(failures.result().is_ok());
+        assert_eq!(
+            b"un caf\xe9 toad\n",
+            &std::fs::read(path("latin1.txt")).unwrap()[..]
+        );
+    }
+
+    #[test]
+    fn invert_and_count() {
+        let poem = "I'm nobody! Who are you?\nAre you nobody, too?\nThen there's a pair of us";
+        let search = |invert, mode| {
+            let search = Search {
+                invert,
+                mode,
+                ..search(Pattern::literal("nobody", true))
+            };
+            let print = PrintOptions {
+                mode,
+                ..PrintOptions::default()
+            };
+            let mut out = Vec::new();
+            let mut printer = Printer::new(&mut out, print);
+            printer.start_file("poem.txt").unwrap();
+            let stats = search
+                .reader(io::Cursor::new(poem), Path::new("poem.txt"), &mut printer)
+                .unwrap();
+            (String::from_utf8(out).unwrap(), stats.matched_lines)
+        };
+        assert_eq!(
+            ("Then there's a pair of us\n".to_string(), 1),
+            search(true, OutputMode::Lines)
+        );
+        assert_eq!(("2\n".to_string(), 2), search(false, OutputMode::Count));
+        assert_eq!(("1\n".to_string(), 1), search(true, OutputMode::Count));
+        // listing files stops at the first match
+        assert_eq!(
+            ("poem.txt\n".to_string(), 1),
+            search(false, OutputMode::FilesWithMatches)
+        );
+        assert_eq!(
+            (String::new(), 1),
+            search(false, OutputMode::FilesWithoutMatch)
+        );
+    }
+
+    #[test]
+    fn binary_files_are_skipped_before_anything_is_printed() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("a.out");
+        // the NUL byte is past the first buffer, but within the window
+        let mut bytes = "a frog\n".repeat(2_000).into_bytes();
+        bytes.extend(b"\0\n");
+        std::fs::write(&path, &bytes).unwrap();
+        let search = Search {
+            binary: BinaryMode::Skip,
+            ..search(Pattern::literal("frog", true))
+        };
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, PrintOptions::default());
+        let stats = search.file(&path, &mut printer).unwrap();
+        assert_eq!((1, 0), (stats.files, stats.matched_lines));
+        assert!(out.is_empty());
+
+        // too late to skip the file, but the search stops at the NUL byte
+        let lines = BINARY_WINDOW / "a frog\n".len() + 100;
+        let mut bytes = "a frog\n".repeat(lines).into_bytes();
+        bytes.extend(b"\0 frog\na frog\n");
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, PrintOptions::default());
+        let reader = BufReader::new(&bytes[..]);
+        let stats = search.reader(reader, &path, &mut printer).unwrap();
+        assert_eq!(lines, stats.matched_lines);
+        assert_eq!("a frog\n".repeat(lines).into_bytes(), out);
+    }
+
+    #[test]
+    fn match_offsets_count_file_bytes() {
+        let mut search = search(Pattern::literal("frog", true));
+        let options = PrintOptions {
+            only_matching: true,
+            byte_offset: true,
+            ..PrintOptions::default()
+        };
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, options);
+        // a byte order mark, and chars of more than one byte in front of the matches
+        let input = "\u{FEFF}café frog\nnaïve frog\n";
+        search
+            .reader(input.as_bytes(), Path::new("-"), &mut printer)
+            .unwrap();
+        assert_eq!(&input[9..13], "frog");
+        assert_eq!(&input[21..25], "frog");
+        assert_eq!("9:frog\n21:frog\n", String::from_utf8(out).unwrap());
+
+        // the same lines in UTF-16 and Latin-1
+        let utf16: Vec<u8> = input.encode_utf16().flat_map(u16::to_le_bytes).collect();
+        let latin1: Vec<u8> = input[3..].chars().map(|c| c as u8).collect();
+        for (bytes, encoding, expected) in [
+            (utf16, Encoding::Auto, "12:frog\n34:frog\n"),
+            (latin1, Encoding::Latin1, "5:frog\n16:frog\n"),
+        ] {
+            search.encoding = encoding;
+            let mut out = Vec::new();
+            let mut printer = Printer::new(&mut out, options);
+            search
+                .reader(&bytes[..], Path::new("-"), &mut printer)
+                .unwrap();
+            assert_eq!(expected, String::from_utf8(out).unwrap());
+        }
+    }
+
+    #[test]
+    fn binary_files() {
+        let input = b"\x7fELF\0\x02 frog\n\xff\xfe frog\n";
+        let search = |binary| {
+            let search = Search {
+                binary,
+                ..search(Pattern::literal("frog", true))
+            };
+            let mut out = Vec::new();
+            let mut printer = Printer::new(&mut out, PrintOptions::default());
+            printer.start_file("a.out").unwrap();
+            let stats = search
+                .reader(&input[..], Path::new("a.out"), &mut printer)
+                .unwrap();
+            (String::from_utf8(out).unwrap(), stats.matched_lines)
+        };
+        assert_eq!(
+            ("Binary file a.out matches\n".to_string(), 1),
+            search(BinaryMode::Quiet)
+        );
+        // bytes that aren't UTF-8 don't stop the search
+        assert_eq!(
+            (
+                "\u{7f}ELF\0\u{2} frog\n\u{FFFD}\u{FFFD} frog\n".to_string(),
+                2
+            ),
+            search(BinaryMode::Text)
+        );
+    }
+
+    #[test]
+    fn search_a_stream() {
+        let input =
+            io::Cursor::new("How public, like a frog\r\nTo tell your name\nTo an admiring bog!");
+        let mut out = Vec::new();
+        let options = PrintOptions {
+            line_numbers: true,
+            ..PrintOptions::default()
+        };
+        let mut printer = Printer::new(&mut out, options);
+        let search = search(Pattern::literal("to", false));
+        search
+            .reader(input, Path::new("poem.txt"), &mut printer)
+            .unwrap();
+        assert_eq!(
+            "2:To tell your name\n3:To an admiring bog!\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+}
@@ -0,0 +1,1095 @@
+use std::fs;
+use std::path::Path;
+
+use super::settings::{self, Setting, Source, Value};
+use super::{
+    BinaryMode, ColorChoice, Encoding, Fold, MinigrepError, Normalization, OutputMode, Pattern,
+    PrintOptions, WalkOptions,
+};
+
+/// What `execute` is asked to do.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub(crate) enum Action {
+    #[default]
+    Search,
+    Help,
+    Version,
+    /// Build the index of a directory.
+    Index,
+    /// Answer the query from the index of a directory.
+    Query,
+    /// Show the settings and where they came from.
+    PrintConfig,
+}
+
+#[derive(Debug, Default)]
+pub struct Config {
+    pub(crate) action: Action,
+    pub(crate) query: String,
+    // files to read the patterns from, one per line, instead of the query
+    pub(crate) pattern_files: Vec<String>,
+    pub(crate) paths: Vec<String>,
+    pub(crate) case_sensitive: bool,
+    pub(crate) smart_case: bool,
+    pub(crate) normalization: Normalization,
+    pub(crate) regex: bool,
+    pub(crate) fuzzy: Option<usize>,
+    pub(crate) invert: bool,
+    pub(crate) replace: Option<String>,
+    pub(crate) dry_run: bool,
+    pub(crate) in_place: bool,
+    pub(crate) print: PrintOptions,
+    pub(crate) color: ColorChoice,
+    pub(crate) walk: WalkOptions,
+    pub(crate) encoding: Encoding,
+    pub(crate) binary: BinaryMode,
+    pub(crate) follow: bool,
+    // what was set in config files, the environment and on the command line, for
+    // --print-config
+    pub(crate) settings: Vec<Setting>,
+    pub(crate) warnings: Vec<String>,
+}
+
+struct Opt {
+    short: Option<char>,
+    long: &'static str,
+    // name of the value in the help text, None for plain switches
+    value: Option<&'static str>,
+    help: &'static str,
+}
+
+const OPTIONS: &[Opt] = &[
+    Opt {
+        short: Some('i'),
+        long: "ignore-case",
+        value: None,
+        help: "Ignore case distinctions",
+    },
+    Opt {
+        short: Some('S'),
+        long: "smart-case",
+        value: None,
+        help: "Ignore case unless the query has an uppercase letter",
+    },
+    Opt {
+        short: None,
+        long: "normalize",
+        value: Some("FORM"),
+        help: "Match Unicode equivalents: none, nfc or nfkc",
+    },
+    Opt {
+        short: Some('E'),
+        long: "regex",
+        value: None,
+        help: "Treat QUERY as a regular expression",
+    },
+    Opt {
+        short: None,
+        long: "fuzzy",
+        value: Some("N"),
+        help: "Allow up to N typos in a match, each hit shows its count like d=1:",
+    },
+    Opt {
+        short: Some('f'),
+        long: "file",
+        value: Some("FILE"),
+        help: "Read the patterns from FILE, one per line, hits show their line like p=3:",
+    },
+    Opt {
+        short: Some('v'),
+        long: "invert-match",
+        value: None,
+        help: "Select the lines that don't match",
+    },
+    Opt {
+        short: None,
+        long: "replace",
+        value: Some("TEXT"),
+        help: "Replace every match with TEXT ($1 for regex groups)",
+    },
+    Opt {
+        short: None,
+        long: "dry-run",
+        value: None,
+        help: "Print the replacements as a unified diff",
+    },
+    Opt {
+        short: None,
+        long: "in-place",
+        value: None,
+        help: "Write the replacements back into the files",
+    },
+    Opt {
+        short: Some('c'),
+        long: "count",
+        value: None,
+        help: "Only print the number of selected lines per file",
+    },
+    Opt {
+        short: Some('l'),
+        long: "files-with-matches",
+        value: None,
+        help: "Only print the names of files with selected lines",
+    },
+    Opt {
+        short: Some('L'),
+        long: "files-without-match",
+        value: None,
+        help: "Only print the names of files without selected lines",
+    },
+    Opt {
+        short: Some('n'),
+        long: "line-number",
+        value: None,
+        help: "Prefix each line with its line number",
+    },
+    Opt {
+        short: Some('b'),
+        long: "byte-offset",
+        value: None,
+        help: "Prefix each line with its byte offset",
+    },
+    Opt {
+        short: Some('o'),
+        long: "only-matching",
+        value: None,
+        help: "Print only the matched parts of a line",
+    },
+    Opt {
+        short: None,
+        long: "color",
+        value: Some("WHEN"),
+        help: "Highlight matches: auto, always or never",
+    },
+    Opt {
+        short: None,
+        long: "json",
+        value: None,
+        help: "Print the results as JSON Lines",
+    },
+    Opt {
+        short: Some('A'),
+        long: "after-context",
+        value: Some("NUM"),
+        help: "Print NUM lines after each match",
+    },
+    Opt {
+        short: Some('B'),
+        long: "before-context",
+        value: Some("NUM"),
+        help: "Print NUM lines before each match",
+    },
+    Opt {
+        short: Some('C'),
+        long: "context",
+        value: Some("NUM"),
+        help: "Print NUM lines before and after each match",
+    },
+    Opt {
+        short: None,
+        long: "include",
+        value: Some("GLOB"),
+        help: "Only search files matching GLOB",
+    },
+    Opt {
+        short: None,
+        long: "exclude",
+        value: Some("GLOB"),
+        help: "Skip files and directories matching GLOB",
+    },
+    Opt {
+        short: None,
+        long: "encoding",
+        value: Some("ENC"),
+        help: "Read files as auto, utf-8, utf-16le, utf-16be or latin1",
+    },
+    Opt {
+        short: Some('a'),
+        long: "text",
+        value: None,
+        help: "Search binary files as if they were text",
+    },
+    Opt {
+        short: Some('I'),
+        long: "skip-binary",
+        value: None,
+        help: "Skip binary files",
+    },
+    Opt {
+        short: None,
+        long: "hidden",
+        value: None,
+        help: "Search hidden files and directories",
+    },
+    Opt {
+        short: None,
+        long: "no-ignore",
+        value: None,
+        help: "Don't respect .gitignore and .ignore files",
+    },
+    Opt {
+        short: None,
+        long: "follow",
+        value: None,
+        help: "Keep reading FILE as it grows, and reopen it when it is rotated",
+    },
+    Opt {
+        short: None,
+        long: "print-config",
+        value: None,
+        help: "Print the settings in effect and where they come from",
+    },
+    Opt {
+        short: Some('h'),
+        long: "help",
+        value: None,
+        help: "Print this help and exit",
+    },
+    Opt {
+        short: Some('V'),
+        long: "version",
+        value: None,
+        help: "Print the version and exit",
+    },
+];
+
+/// The text `--help` prints.
+pub fn usage() -> String {
+    let mut usage = String::from(concat!(
+        "Usage: minigrep [OPTIONS] QUERY [FILE]...\n",
+        "       minigrep [OPTIONS] -f PATTERNS [FILE]...\n",
+        "       minigrep index [DIR]\n",
+        "       minigrep query [OPTIONS] QUERY [DIR]\n",
+        "\n",
+        "With no FILE, or when FILE is -, read standard input.\n",
+        "'index' writes a word index of DIR (default .) into DIR/.minigrep-index, 'query'\n",
+        "looks up lines with all the words of QUERY in it, \"quoted words\" as a phrase.\n",
+        "To search for the word index or query, put -- in front of it.\n",
+        "\n",
+        "Defaults for the options are read from ~/.config/minigrep/config.toml, then from\n",
+        "the closest .minigreprc, then from MINIGREP_* variables (e.g. MINIGREP_LINE_NUMBER=1),\n",
+        "the later ones win and the command line wins over all of them. The files are TOML\n",
+        "with the long options as keys, e.g. 'context = 2' or 'exclude = [\"target\"]'.\n",
+        "A switch turned on there is turned off again with --no-SWITCH, e.g. --no-line-number.\n",
+        "\n",
+        "Options:\n",
+    ));
+    for opt in OPTIONS {
+        let short = match opt.short {
+            Some(c) => format!("-{}, ", c),
+            None => String::from("    "),
+        };
+        let long = match opt.value {
+            Some(value) => format!("--{} {}", opt.long, value),
+            None => format!("--{}", opt.long),
+        };
+        usage.push_str(&format!("  {}{:<24}{}\n", short, long, opt.help));
+    }
+    usage
+}
+
+impl Config {
+    /// Parse the command line. The first item is the path of the binary and gets skipped.
+    /// Only the arguments count, see `from_env` for the config files and the environment.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// let args = ["minigrep", "-ni", "--context=2", "frog", "poem.txt"];
+    /// let config = rust_book::Config::new(args.map(String::from)).unwrap();
+    /// ```
+    pub fn new<I>(args: I) -> Result<Config, MinigrepError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Config::with_settings(args, Vec::new())
+    }
+
+    /// Parse the command line on top of the user config file, the project config file and
+    /// the `MINIGREP_*` environment variables, which is what the binary does.
+    pub fn from_env<I>(args: I) -> Result<Config, MinigrepError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        Config::with_settings(args, settings::discover()?)
+    }
+
+    /// Parse the command line on top of `settings`, which are sorted from the lowest to the
+    /// highest precedence.
+    pub(crate) fn with_settings<I>(args: I, settings: Vec<Setting>) -> Result<Config, MinigrepError>
+    where
+        I: IntoIterator<Item = String>,
+    {
+        let mut args = args.into_iter();
+        args.next();
+        let mut config = Config {
+            case_sensitive: true,
+            ..Config::default()
+        };
+        for setting in settings {
+            config.record(setting)?;
+        }
+        let mut positional = Vec::new();
+        let mut args = args.peekable();
+        // 'minigrep -- index' still searches for the word
+        match args.peek().map(String::as_str) {
+            Some("index") => config.action = Action::Index,
+            Some("query") => config.action = Action::Query,
+            _ => {}
+        }
+        if matches!(config.action, Action::Index | Action::Query) {
+            args.next();
+        }
+        while let Some(arg) = args.next() {
+            if arg == "--" {
+                // everything after this is a query or a file, even if it starts with '-'
+                positional.extend(args.by_ref());
+            } else if let Some(long) = arg.strip_prefix("--") {
+                let (name, value) = match long.split_once('=') {
+                    Some((name, value)) => (name, Some(value.to_string())),
+                    None => (long, None),
+                };
+                if let Some(opt) = switched_off(name) {
+                    if value.is_some() {
+                        return Err(usage_error(format!(
+                            "option '--{}' doesn't take a value",
+                            name
+                        )));
+                    }
+                    config.record(Setting {
+                        name: opt.long.to_string(),
+                        value: Value::Switch(false),
+                        source: Source::CommandLine,
+                    })?;
+                    continue;
+                }
+                let opt = OPTIONS
+                    .iter()
+                    .find(|opt| opt.long == name)
+                    .ok_or_else(|| usage_error(format!("unknown option '--{}'", name)))?;
+                let value = match (opt.value, value) {
+                    (Some(_), Some(value)) => Some(value),
+                    (Some(_), None) => Some(args.next().ok_or_else(|| missing_value(opt))?),
+                    (None, Some(_)) => {
+                        return Err(usage_error(format!(
+                            "option '--{}' doesn't take a value",
+                            opt.long
+                        )))
+                    }
+                    (None, None) => None,
+                };
+                config.set(opt, value)?;
+            } else if arg.len() > 1 && arg.starts_with('-') {
+                // a cluster of short flags like '-ni' or '-nA3'
+                for (i, c) in arg.char_indices().skip(1) {
+                    let opt = OPTIONS
+                        .iter()
+                        .find(|opt| opt.short == Some(c))
+                        .ok_or_else(|| usage_error(format!("unknown option '-{}'", c)))?;
+                    if opt.value.is_none() {
+                        config.set(opt, None)?;
+                        continue;
+                    }
+                    let rest = &arg[i + c.len_utf8()..];
+                    let value = if rest.is_empty() {
+                        args.next().ok_or_else(|| missing_value(opt))?
+                    } else {
+                        rest.to_string()
+                    };
+                    config.set(opt, Some(value))?;
+                    break;
+                }
+            } else {
+                positional.push(arg);
+            }
+        }
+
+        // in the order of precedence, so the command line wins
+        for setting in config.settings.clone() {
+            config.apply_setting(&setting)?;
+        }
+
+        let mut positional = positional.into_iter();
+        match config.action {
+            Action::Help | Action::Version | Action::PrintConfig => return Ok(config),
+            Action::Index | Action::Query => {
+                if config.action == Action::Query {
+                    config.query = positional.next().ok_or(MinigrepError::MissingQuery)?;
+                }
+                config.paths = vec![positional.next().unwrap_or_else(|| String::from("."))];
+                if positional.next().is_some() {
+                    return Err(usage_error(String::from(
+                        "'index' and 'query' take a single directory",
+                    )));
+                }
+                return Ok(config);
+            }
+            Action::Search => {}
+        }
+        if config.replace.is_none() && (config.dry_run || config.in_place) {
+            return Err(usage_error(String::from(
+                "'--dry-run' and '--in-place' need '--replace'",
+            )));
+        }
+        if config.replace.is_some() && config.invert {
+            return Err(usage_error(String::from(
+                "'--replace' can't be combined with '--invert-match'",
+            )));
+        }
+        if config.pattern_files.is_empty() {
+            config.query = positional.next().ok_or(MinigrepError::MissingQuery)?;
+        }
+        // without any files we read from stdin
+        config.paths = positional.collect();
+        if config.follow {
+            if config.paths.len() != 1 || config.paths[0] == "-" {
+                return Err(usage_error(String::from("'--follow' needs a single file")));
+            }
+            if config.print.mode != OutputMode::Lines || config.dry_run || config.in_place {
+                return Err(usage_error(String::from(
+                    "'--follow' only prints lines, it can't count, list files or rewrite them",
+                )));
+            }
+        }
+        Ok(config)
+    }
+
+    // An option from the command line. The ones that are settings too only take effect
+    // once all of them are in, see `apply_setting`.
+    fn set(&mut self, opt: &Opt, value: Option<String>) -> Result<(), MinigrepError> {
+        if NOT_SETTINGS.contains(&opt.long) {
+            return self.apply(opt, value);
+        }
+        let value = match value {
+            None => Value::Switch(true),
+            Some(value) if LISTS.contains(&opt.long) => Value::List(vec![value]),
+            Some(value) => Value::Text(value),
+        };
+        self.record(Setting {
+            name: opt.long.to_string(),
+            value,
+            source: Source::CommandLine,
+        })
+    }
+
+    // Check a setting and remember it in place of the ones with less precedence. Lists are
+    // not replaced, they add up.
+    fn record(&mut self, setting: Setting) -> Result<(), MinigrepError> {
+        let invalid = |message: String| settings::invalid(&setting.source, message);
+        let Some(opt) = OPTIONS
+            .iter()
+            .find(|opt| opt.long == setting.name && !NOT_SETTINGS.contains(&opt.long))
+        else {
+            // the prefix isn't ours alone, and a variable can't be fixed as easily as a file
+            if let Source::Env(name) = &setting.source {
+                self.warnings
+                    .push(format!("ignoring {}, it is not a setting", name));
+                return Ok(());
+            }
+            return Err(invalid(format!("unknown setting '{}'", setting.name)));
+        };
+        let is_list = LISTS.contains(&opt.long);
+        let value = match (setting.value, opt.value) {
+            (Value::Switch(on), None) => Value::Switch(on),
+            // environment variables are always text
+            (Value::Text(text), None) => match text.to_ascii_lowercase().as_str() {
+                "1" | "true" | "yes" | "on" => Value::Switch(true),
+                "" | "0" | "false" | "no" | "off" => Value::Switch(false),
+                _ => return Err(invalid(format!("'{}' is not on or off", text))),
+            },
+            (Value::Text(text), Some(_)) if is_list => match setting.source {
+                Source::Env(_) => Value::List(text.split(',').map(String::from).collect()),
+                _ => Value::List(vec![text]),
+            },
+            (Value::Text(text), Some(_)) => Value::Text(text),
+            (Value::List(items), Some(_)) if is_list => Value::List(items),
+            (_, None) => {
+                return Err(invalid(format!(
+                    "'{}' is a switch, it takes true or false",
+                    opt.long
+                )))
+            }
+            (_, Some(value)) => {
+                return Err(invalid(format!("'{}' takes a single {}", opt.long, value)))
+            }
+        };
+        let source = setting.source;
+        if let Value::List(items) = &value {
+            if let Some(Setting {
+                value: Value::List(old),
+                ..
+            }) = self
+                .settings
+                .iter_mut()
+                .find(|s| s.name == opt.long && s.source == source)
+            {
+                old.extend(items.iter().cloned());
+                return Ok(());
+            }
+        } else {
+            self.settings.retain(|s| s.name != opt.long);
+        }
+        self.settings.push(Setting {
+            name: opt.long.to_string(),
+            value,
+            source,
+        });
+        Ok(())
+    }
+
+    fn apply_setting(&mut self, setting: &Setting) -> Result<(), MinigrepError> {
+        let opt = OPTIONS
+            .iter()
+            .find(|opt| opt.long == setting.name)
+            .expect("settings are checked when they are recorded");
+        let result = match &setting.value {
+            Value::Switch(true) => self.apply(opt, None),
+            Value::Switch(false) => Ok(()),
+            Value::Text(text) => self.apply(opt, Some(text.clone())),
+            Value::List(items) => items
+                .iter()
+                .try_for_each(|item| self.apply(opt, Some(item.clone()))),
+        };
+        // say where the bad value is from, unless it is the command line
+        result.map_err(|e| match e {
+            MinigrepError::Usage(message) if setting.source != Source::CommandLine => {
+                settings::invalid(&setting.source, message)
+            }
+            e => e,
+        })
+    }
+
+    /// The settings in effect, as TOML with where they came from, for `--print-config`.
+    pub(crate) fn describe(&self) -> String {
+        let mut out = String::from("# from config files, MINIGREP_* variables and the command line,\n# later sources win\n");
+        for setting in &self.settings {
+            let value = match &setting.value {
+                Value::Switch(on) => on.to_string(),
+                Value::Text(text) if text.parse::<usize>().is_ok() => text.clone(),
+                Value::Text(text) => toml::Value::from(text.as_str()).to_string(),
+                Value::List(items) => toml::Value::from(items.clone()).to_string(),
+            };
+            let line = format!("{} = {}", setting.name, value);
+            out.push_str(&format!("{:<32} # {}\n", line, setting.source));
+        }
+        out
+    }
+
+    fn apply(&mut self, opt: &Opt, value: Option<String>) -> Result<(), MinigrepError> {
+        let value = value.unwrap_or_default();
+        match opt.long {
+            "ignore-case" => self.case_sensitive = false,
+            "smart-case" => self.smart_case = true,
+            "normalize" => self.normalization = value.parse().map_err(MinigrepError::Usage)?,
+            "regex" => self.regex = true,
+            "fuzzy" => self.fuzzy = Some(number(opt, &value)?),
+            "file" => self.pattern_files.push(value),
+            "invert-match" => self.invert = true,
+            "replace" => self.replace = Some(value),
+            "dry-run" => self.dry_run = true,
+            "in-place" => self.in_place = true,
+            "count" => self.print.mode = OutputMode::Count,
+            "files-with-matches" => self.print.mode = OutputMode::FilesWithMatches,
+            "files-without-match" => self.print.mode = OutputMode::FilesWithoutMatch,
+            "line-number" => self.print.line_numbers = true,
+            "byte-offset" => self.print.byte_offset = true,
+            "after-context" => self.print.after = number(opt, &value)?,
+            "before-context" => self.print.before = number(opt, &value)?,
+            "context" => {
+                self.print.before = number(opt, &value)?;
+                self.print.after = self.print.before;
+            }
+            "only-matching" => self.print.only_matching = true,
+            "color" => self.color = value.parse().map_err(MinigrepError::Usage)?,
+            "json" => self.print.json = true,
+            "include" => self.walk.include.push(value),
+            "exclude" => self.walk.exclude.push(value),
+            "hidden" => self.walk.hidden = true,
+            "encoding" => self.encoding = value.parse().map_err(MinigrepError::Usage)?,
+            "text" => self.binary = BinaryMode::Text,
+            "skip-binary" => self.binary = BinaryMode::Skip,
+            "no-ignore" => self.walk.no_ignore = true,
+            "follow" => self.follow = true,
+            "print-config" => self.action = Action::PrintConfig,
+            "help" => self.action = Action::Help,
+            "version" => self.action = Action::Version,
+            _ => unreachable!("option '--{}' is not handled", opt.long),
+        }
+        Ok(())
+    }
+
+    /// What was wrong with the settings, but not so wrong that we stopped, like a
+    /// `MINIGREP_*` variable that is not a setting. For the caller to show.
+    pub fn warnings(&self) -> &[String] {
+        &self.warnings
+    }
+
+    /// Compile the query, or the patterns from the `-f` files, into the pattern `execute`
+    /// searches with. The lines of the files are the patterns in order, blank ones included,
+    /// so with more than one file they are counted on from one file to the next.
+    pub fn pattern(&self) -> Result<Pattern, MinigrepError> {
+        let mut patterns = Vec::new();
+        if self.pattern_files.is_empty() {
+            patterns.push(self.query.clone());
+        }
+        for file in &self.pattern_files {
+            let contents =
+                fs::read_to_string(file).map_err(|e| MinigrepError::read(Path::new(file), e))?;
+            // empty lines stay, so a hit's pattern index tells its line, `multi` skips them
+            patterns.extend(contents.lines().map(String::from));
+        }
+        let ignore_case = !self.case_sensitive
+            || (self.smart_case && !patterns.iter().any(|p| has_uppercase(p, self.regex)));
+        let fold = Fold {
+            ignore_case,
+            normalization: self.normalization,
+        };
+        if let Some(max_distance) = self.fuzzy {
+            let queries: Vec<_> = patterns
+                .iter()
+                .filter(|p| patterns.len() == 1 || !p.is_empty())
+                .collect();
+            return match queries[..] {
+                [query] if !self.regex => Ok(Pattern::fuzzy(query, max_distance, fold)),
+                _ => Err(usage_error(String::from(
+                    "'--fuzzy' needs a single query that is not a regex",
+                ))),
+            };
+        }
+        Ok(Pattern::multi(&patterns, fold, self.regex)?)
+    }
+}
+
+// For smart case. In a regex the letter after a backslash is syntax, like in '\W' or '\S'.
+fn has_uppercase(pattern: &str, regex: bool) -> bool {
+    let mut escaped = false;
+    pattern.chars().any(|c| {
+        let upper = c.is_uppercase() && !escaped;
+        escaped = regex && c == '\\' && !escaped;
+        upper
+    })
+}
+
+// options that only make sense for a single run, so they can't be set as defaults
+// The switch `--no-NAME` turns off, whether a config file, a variable or an earlier argument
+// turned it on. The options that aren't settings can't be turned off.
+fn switched_off(name: &str) -> Option<&'static Opt> {
+    let name = name.strip_prefix("no-")?;
+    OPTIONS
+        .iter()
+        .find(|opt| opt.long == name && opt.value.is_none() && !NOT_SETTINGS.contains(&opt.long))
+}
+
+const NOT_SETTINGS: &[&str] = &[
+    "file",
+    "replace",
+    "dry-run",
+    "in-place",
+    "follow",
+    "print-config",
+    "help",
+    "version",
+];
+
+// options that can be given more than once, each time adding to the list
+const LISTS: &[&str] = &["include", "exclude"];
+
+fn usage_error(message: String) -> MinigrepError {
+    MinigrepError::Usage(message)
+}
+
+fn missing_value(opt: &Opt) -> MinigrepError {
+    usage_error(format!(
+        "option '--{}' requires a value {}",
+        opt.long,
+        opt.value.unwrap_or_default()
+    ))
+}
+
+fn number(opt: &Opt, value: &str) -> Result<usize, MinigrepError> {
+    value.parse().map_err(|_| {
+        usage_error(format!(
+            "invalid number '{}' for option '--{}'",
+            value, opt.long
+        ))
+    })
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::path::PathBuf;
+
+    fn parse(args: &[&str]) -> Result<Config, MinigrepError> {
+        // without the settings of whoever runs the tests
+        Config::with_settings(
+            std::iter::once("minigrep")
+                .chain(args.iter().copied())
+                .map(String::from),
+            Vec::new(),
+        )
+    }
+
+    #[test]
+    fn combined_short_flags() {
+        let config = parse(&["-niA3", "frog", "poem.txt"]).unwrap();
+        assert!(!config.case_sensitive);
+        assert!(config.print.line_numbers);
+        assert_eq!(3, config.print.after);
+        assert_eq!(0, config.print.before);
+
+        let config = parse(&["-nC", "2", "frog", "poem.txt"]).unwrap();
+        assert_eq!((2, 2), (config.print.before, config.print.after));
+    }
+
+    #[test]
+    fn long_options_and_multiple_files() {
+        let config = parse(&[
+            "--ignore-case",
+            "--context=1",
+            "--include",
+            "*.rs",
+            "fn",
+            "src",
+            "tests",
+        ])
+        .unwrap();
+        assert!(!config.case_sensitive);
+        assert_eq!(1, config.print.after);
+        assert_eq!(vec!["*.rs"], config.walk.include);
+        assert_eq!("fn", config.query);
+        assert_eq!(vec!["src", "tests"], config.paths);
+    }
+
+    #[test]
+    fn color_choice() {
+        assert_eq!(ColorChoice::Auto, parse(&["a"]).unwrap().color);
+        assert_eq!(
+            ColorChoice::Never,
+            parse(&["--color=never", "a"]).unwrap().color
+        );
+        assert_eq!(
+            "invalid color 'sometimes', expected auto, always or never",
+            parse(&["--color", "sometimes", "a"])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn double_dash_ends_options() {
+        let config = parse(&["-n", "--", "-i", "--help"]).unwrap();
+        assert!(config.case_sensitive);
+        assert_eq!("-i", config.query);
+        assert_eq!(vec!["--help"], config.paths);
+    }
+
+    #[test]
+    fn no_file_means_stdin() {
+        assert!(parse(&["frog"]).unwrap().paths.is_empty());
+        assert_eq!(vec!["-"], parse(&["frog", "-"]).unwrap().paths);
+    }
+
+    #[test]
+    fn pattern_files_replace_the_query() {
+        let config = parse(&["-f", "forbidden.txt", "src", "tests"]).unwrap();
+        assert_eq!(vec!["forbidden.txt"], config.pattern_files);
+        assert_eq!("", config.query);
+        assert_eq!(vec!["src", "tests"], config.paths);
+
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("patterns.txt");
+        fs::write(&path, "unwrap\n\nexpect\n").unwrap();
+        let config = parse(&["-f", path.to_str().unwrap()]).unwrap();
+        let pattern = config.pattern().unwrap();
+        // the blank line is left out, but still counts for the index
+        assert_eq!(vec![(2, 2..8)], pattern.find_patterns("x.expect(y)"));
+        assert!(!pattern.is_match("everything"));
+
+        let error = parse(&["-f", "no-such-patterns.txt"])
+            .unwrap()
+            .pattern()
+            .unwrap_err();
+        assert!(matches!(error, MinigrepError::NotFound { .. }));
+    }
+
+    #[test]
+    fn replace_options() {
+        let config = parse(&["-E", "--replace", "$1", "--dry-run", "(frog)", "poem.txt"]).unwrap();
+        assert_eq!(Some("$1"), config.replace.as_deref());
+        assert!(config.dry_run);
+        assert_eq!(
+            "'--dry-run' and '--in-place' need '--replace'",
+            parse(&["--in-place", "a", "b"]).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "'--replace' can't be combined with '--invert-match'",
+            parse(&["-v", "--replace=x", "a", "b"])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn smart_case() {
+        let pattern = |args: &[&str]| parse(args).unwrap().pattern().unwrap();
+        assert!(pattern(&["-S", "frog"]).is_match("FROG"));
+        assert!(!pattern(&["-S", "Frog"]).is_match("FROG"));
+        assert!(pattern(&["-S", "-E", r"fr\Wg"]).is_match("FR-G"));
+        // '-i' always wins
+        assert!(pattern(&["-S", "-i", "Frog"]).is_match("FROG"));
+    }
+
+    #[test]
+    fn normalization() {
+        let config = parse(&["-i", "--normalize", "nfkc", "ﬁle"]).unwrap();
+        assert_eq!(Normalization::Nfkc, config.normalization);
+        assert!(config.pattern().unwrap().is_match("FILE"));
+        assert_eq!(
+            "invalid normalization 'nfx', expected none, nfc or nfkc",
+            parse(&["--normalize=nfx", "a"]).unwrap_err().to_string()
+        );
+    }
+
+    #[test]
+    fn fuzzy() {
+        let pattern = parse(&["--fuzzy", "1", "-i", "nobody"])
+            .unwrap()
+            .pattern()
+            .unwrap();
+        assert_eq!(
+            vec![(1, 8..13)],
+            pattern.find_distances("Are you NBODY, too?")
+        );
+        assert_eq!(
+            "'--fuzzy' needs a single query that is not a regex",
+            parse(&["--fuzzy=2", "-E", "a+"])
+                .unwrap()
+                .pattern()
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn encodings_and_binary_files() {
+        let config = parse(&["--encoding=UTF-16LE", "-I", "frog"]).unwrap();
+        assert_eq!(Encoding::Utf16Le, config.encoding);
+        assert_eq!(BinaryMode::Skip, config.binary);
+        assert_eq!(BinaryMode::Text, parse(&["-a", "frog"]).unwrap().binary);
+        assert_eq!(
+            "invalid encoding 'ascii', expected auto, utf-8, utf-16le, utf-16be or latin1",
+            parse(&["--encoding", "ascii", "frog"])
+                .unwrap_err()
+                .to_string()
+        );
+    }
+
+    #[test]
+    fn help_and_version_need_no_query() {
+        assert_eq!(Action::Help, parse(&["--help"]).unwrap().action);
+        assert_eq!(Action::Version, parse(&["-V"]).unwrap().action);
+    }
+
+    #[test]
+    fn layered_settings() {
+        let user = PathBuf::from("config.toml");
+        let mut layers = settings::parse(
+            "line-number = true\ncontext = 2\nexclude = [\"target\"]\nignore-case = true",
+            Source::File(user),
+        )
+        .unwrap();
+        layers.extend(
+            settings::parse(
+                "context = 1\nignore-case = false",
+                Source::File(PathBuf::from(".minigreprc")),
+            )
+            .unwrap(),
+        );
+        let vars = [
+            ("MINIGREP_EXCLUDE", "*.lock,*.min.js"),
+            ("MINIGREP_COLOR", "never"),
+        ];
+        layers.extend(settings::vars(vars.map(|(k, v)| (k.into(), v.into()))).unwrap());
+        let args = ["minigrep", "--exclude", "*.svg", "--color=always", "frog"];
+        let config = Config::with_settings(args.map(String::from), layers).unwrap();
+
+        assert!(config.print.line_numbers);
+        // the project file wins over the user file
+        assert_eq!((1, 1), (config.print.before, config.print.after));
+        assert!(config.case_sensitive);
+        // and the command line over everything
+        assert_eq!(ColorChoice::Always, config.color);
+        // lists add up
+        assert_eq!(
+            vec!["target", "*.lock", "*.min.js", "*.svg"],
+            config.walk.exclude
+        );
+        assert_eq!(
+            "# from config files, MINIGREP_* variables and the command line,\n# later sources win\n\
+             exclude = [\"target\"]             # config.toml\n\
+             line-number = true               # config.toml\n\
+             context = 1                      # .minigreprc\n\
+             ignore-case = false              # .minigreprc\n\
+             exclude = [\"*.lock\", \"*.min.js\"] # MINIGREP_EXCLUDE\n\
+             exclude = [\"*.svg\"]              # command line\n\
+             color = \"always\"                 # command line\n",
+            config.describe()
+        );
+    }
+
+    #[test]
+    fn switches_can_be_turned_off() {
+        let file = Source::File(PathBuf::from(".minigreprc"));
+        let layer = settings::parse("ignore-case = true\nline-number = true\ncount = true", file);
+        let args = ["minigrep", "--no-ignore-case", "--no-count", "-b", "frog"];
+        let config = Config::with_settings(args.map(String::from), layer.unwrap()).unwrap();
+        assert!(config.case_sensitive);
+        assert!(config.print.line_numbers);
+        assert_eq!(OutputMode::Lines, config.print.mode);
+        assert!(config.describe().contains("count = false"));
+
+        // the last one wins
+        assert!(
+            !parse(&["-n", "--no-line-number", "a"])
+                .unwrap()
+                .print
+                .line_numbers
+        );
+        assert!(
+            parse(&["--no-line-number", "-n", "a"])
+                .unwrap()
+                .print
+                .line_numbers
+        );
+        // '--no-ignore' is a switch of its own
+        assert!(parse(&["--no-ignore", "a"]).unwrap().walk.no_ignore);
+        assert!(
+            !parse(&["--no-ignore", "--no-no-ignore", "a"])
+                .unwrap()
+                .walk
+                .no_ignore
+        );
+        let error = |args| parse(args).unwrap_err().to_string();
+        assert_eq!(
+            "unknown option '--no-context'",
+            error(&["--no-context", "a"])
+        );
+        assert_eq!(
+            "unknown option '--no-in-place'",
+            error(&["--no-in-place", "a"])
+        );
+        assert_eq!(
+            "option '--no-json' doesn't take a value",
+            error(&["--no-json=1", "a"])
+        );
+    }
+
+    #[test]
+    fn bad_settings_name_their_source() {
+        let source = Source::Env(String::from("MINIGREP_CONTEXT"));
+        let layer = |name: &str, value: &str| {
+            vec![Setting {
+                name: name.to_string(),
+                value: Value::Text(value.to_string()),
+                source: source.clone(),
+            }]
+        };
+        let error = |layer| {
+            Config::with_settings(["minigrep", "a"].map(String::from), layer)
+                .unwrap_err()
+                .to_string()
+        };
+        assert_eq!(
+            "MINIGREP_CONTEXT: invalid number 'lots' for option '--context'",
+            error(layer("context", "lots"))
+        );
+        assert_eq!(
+            "MINIGREP_CONTEXT: 'maybe' is not on or off",
+            error(layer("hidden", "maybe"))
+        );
+        // a variable we don't know is left alone, an unknown key in a file is a mistake
+        let config =
+            Config::with_settings(["minigrep", "a"].map(String::from), layer("in-place", "1"))
+                .unwrap();
+        assert!(!config.in_place);
+        assert_eq!(
+            ["ignoring MINIGREP_CONTEXT, it is not a setting"],
+            config.warnings()
+        );
+        let file = Source::File(PathBuf::from(".minigreprc"));
+        let unknown = settings::parse("in-place = true", file).unwrap();
+        assert_eq!(".minigreprc: unknown setting 'in-place'", error(unknown));
+        let list = settings::parse("json = [\"yes\"]", Source::CommandLine).unwrap();
+        assert_eq!(
+            "command line: 'json' is a switch, it takes true or false",
+            error(list)
+        );
+        assert_eq!(
+            Action::PrintConfig,
+            parse(&["--print-config"]).unwrap().action
+        );
+    }
+
+    #[test]
+    fn follow_needs_a_single_file() {
+        assert!(parse(&["--follow", "frog", "app.log"]).unwrap().follow);
+        let error = |args| parse(args).unwrap_err().to_string();
+        assert_eq!(
+            "'--follow' needs a single file",
+            error(&["--follow", "frog"])
+        );
+        assert_eq!(
+            "'--follow' needs a single file",
+            error(&["--follow", "frog", "a", "b"])
+        );
+        assert!(error(&["--follow", "-c", "frog", "app.log"]).starts_with("'--follow' only prints"));
+    }
+
+    #[test]
+    fn index_and_query() {
+        let config = parse(&["index"]).unwrap();
+        assert_eq!(Action::Index, config.action);
+        assert_eq!(vec!["."], config.paths);
+        let config = parse(&["query", "-n", "\"pair of us\"", "poems"]).unwrap();
+        assert_eq!(Action::Query, config.action);
+        assert_eq!("\"pair of us\"", config.query);
+        assert_eq!(vec!["poems"], config.paths);
+        assert!(parse(&["query", "frog", "a", "b"]).is_err());
+        // only as the first argument, or after '--', they are plain queries
+        assert_eq!(Action::Search, parse(&["-i", "index", "a"]).unwrap().action);
+        let config = parse(&["--", "query", "a"]).unwrap();
+        assert_eq!(
+            (Action::Search, "query"),
+            (config.action, &config.query[..])
+        );
+    }
+
+    #[test]
+    fn usage_errors() {
+        assert_eq!(
+            "Didn't get a query string",
+            parse(&[]).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "unknown option '-x'",
+            parse(&["-nx", "a", "b"]).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "unknown option '--colour'",
+            parse(&["--colour", "a", "b"]).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "option '--after-context' requires a value NUM",
+            parse(&["-A"]).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "invalid number 'many' for option '--context'",
+            parse(&["-C", "many", "a", "b"]).unwrap_err().to_string()
+        );
+        assert_eq!(
+            "option '--hidden' doesn't take a value",
+            parse(&["--hidden=yes", "a", "b"]).unwrap_err().to_string()
+        );
+    }
+}
@@ -0,0 +1,474 @@
+use std::fmt;
+use std::io::{self, BufRead};
+use std::str::FromStr;
+
+/// How the bytes of a file are turned into text.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Encoding {
+    /// UTF-16 if the file starts with a byte order mark, UTF-8 otherwise.
+    #[default]
+    Auto,
+    Utf8,
+    Utf16Le,
+    Utf16Be,
+    /// ISO 8859-1, every byte is the char with the same number.
+    Latin1,
+}
+
+impl FromStr for Encoding {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Encoding, String> {
+        match s.to_ascii_lowercase().replace('_', "-").as_str() {
+            "auto" => Ok(Encoding::Auto),
+            "utf-8" | "utf8" => Ok(Encoding::Utf8),
+            "utf-16le" | "utf16le" => Ok(Encoding::Utf16Le),
+            "utf-16be" | "utf16be" => Ok(Encoding::Utf16Be),
+            "latin-1" | "latin1" | "iso-8859-1" => Ok(Encoding::Latin1),
+            _ => Err(format!(
+                "invalid encoding '{}', expected auto, utf-8, utf-16le, utf-16be or latin1",
+                s
+            )),
+        }
+    }
+}
+
+impl fmt::Display for Encoding {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        let name = match self {
+            Encoding::Auto => "auto",
+            Encoding::Utf8 => "UTF-8",
+            Encoding::Utf16Le => "UTF-16LE",
+            Encoding::Utf16Be => "UTF-16BE",
+            Encoding::Latin1 => "Latin-1",
+        };
+        f.write_str(name)
+    }
+}
+
+/// What to do with files that contain NUL bytes, which text files never do.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum BinaryMode {
+    /// Don't print the lines, only that the file matches.
+    #[default]
+    Quiet,
+    /// Skip the file, as if it had no matches. Only the start of the file is checked for
+    /// that, a NUL byte further in stops the search there.
+    Skip,
+    /// Search it like any other file.
+    Text,
+}
+
+/// Reads the lines of a file in the given encoding.
+///
+/// Bytes that are not valid in the encoding become U+FFFD, so a stray byte can't stop a
+/// search. Offsets and lengths are counted in the bytes of the file, not of the decoded text.
+pub(crate) struct LineReader<R> {
+    reader: R,
+    encoding: Encoding,
+    raw: Vec<u8>,
+    started: bool,
+    // the byte order mark, until the line after it is handed out
+    bom: usize,
+    // where the text of the last line starts, and how much of the file we handed out
+    line_offset: usize,
+    consumed: usize,
+    binary: bool,
+}
+
+impl<R: BufRead> LineReader<R> {
+    pub(crate) fn new(reader: R, encoding: Encoding) -> LineReader<R> {
+        LineReader {
+            reader,
+            encoding,
+            raw: Vec::new(),
+            started: false,
+            bom: 0,
+            line_offset: 0,
+            consumed: 0,
+            binary: false,
+        }
+    }
+
+    /// Whether we have seen a NUL byte so far. UTF-16 text is full of them, so it never
+    /// counts as binary.
+    pub(crate) fn is_binary(&self) -> bool {
+        self.binary
+    }
+
+    /// Read the next line (with its line ending) into `line`, like `BufRead::read_line`.
+    /// Returns how many bytes of the file it took, 0 at the end.
+    pub(crate) fn read_line(&mut self, line: &mut String) -> io::Result<usize> {
+        self.next_line(line, false)
+    }
+
+    /// The byte offset in the file of the line `read_line` just read, past the byte order
+    /// mark. See `encoded_len` for the offset of a hit in the line.
+    pub(crate) fn line_offset(&self) -> usize {
+        self.line_offset
+    }
+
+    /// The encoding the lines are decoded from, which in auto mode the start of the file
+    /// decides.
+    pub(crate) fn encoding(&mut self) -> io::Result<Encoding> {
+        if !self.started {
+            self.bom = self.start(false)?;
+        }
+        Ok(self.encoding)
+    }
+
+    /// Like `read_line`, for a file that is still being written: a line whose line ending
+    /// isn't there yet is held back, and 0 returned, until a later call reads the rest.
+    pub(crate) fn read_finished_line(&mut self, line: &mut String) -> io::Result<usize> {
+        self.next_line(line, true)
+    }
+
+    pub(crate) fn get_mut(&mut self) -> &mut R {
+        &mut self.reader
+    }
+
+    fn next_line(&mut self, line: &mut String, finished: bool) -> io::Result<usize> {
+        line.clear();
+        if !self.started {
+            self.bom = self.start(finished)?;
+            if !self.started {
+                return Ok(0);
+            }
+        }
+        match self.encoding {
+            Encoding::Utf16Le | Encoding::Utf16Be => self.read_utf16_line()?,
+            _ => {
+                self.reader.read_until(b'\n', &mut self.raw)?;
+                self.binary |= self.raw.contains(&0);
+            }
+        }
+        if self.raw.is_empty() || (finished && !self.is_finished()) {
+            return Ok(0);
+        }
+        match self.encoding {
+            Encoding::Latin1 => line.extend(self.raw.iter().map(|&b| char::from(b))),
+            Encoding::Utf16Le | Encoding::Utf16Be => {
+                let units = self.raw.chunks(2).map(|pair| match (self.encoding, pair) {
+                    (Encoding::Utf16Le, [low, high]) => u16::from_le_bytes([*low, *high]),
+                    (_, [high, low]) => u16::from_be_bytes([*high, *low]),
+                    // half a code unit at the end of the file
+                    _ => 0xFFFD,
+                });
+                line.extend(char::decode_utf16(units).map(|c| c.unwrap_or('\u{FFFD}')));
+            }
+            _ => line.push_str(&String::from_utf8_lossy(&self.raw)),
+        }
+        let read = std::mem::take(&mut self.bom) + self.raw.len();
+        self.line_offset = self.consumed + read - self.raw.len();
+        self.consumed += read;
+        self.raw.clear();
+        Ok(read)
+    }
+
+    // Whether the line read so far ends with a newline of the encoding.
+    fn is_finished(&self) -> bool {
+        match self.encoding {
+            Encoding::Utf16Le => {
+                self.raw.len().is_multiple_of(2) && self.raw.ends_with(&[b'\n', 0])
+            }
+            Encoding::Utf16Be => {
+                self.raw.len().is_multiple_of(2) && self.raw.ends_with(&[0, b'\n'])
+            }
+            _ => self.raw.ends_with(b"\n"),
+        }
+    }
+
+    // Look at the start of the file: skip a byte order mark (and let it pick the encoding
+    // in auto mode), and check the first block for NUL bytes. A file that is still empty,
+    // or only has the start of a mark while it is still being written, gets looked at again
+    // on the next read.
+    fn start(&mut self, growing: bool) -> io::Result<usize> {
+        let head = self.reader.fill_buf()?;
+        let marks: [&[u8]; 3] = [&[0xEF, 0xBB, 0xBF], &[0xFF, 0xFE], &[0xFE, 0xFF]];
+        if head.is_empty()
+            || growing
+                && marks
+                    .iter()
+                    .any(|m| head.len() < m.len() && m.starts_with(head))
+        {
+            return Ok(0);
+        }
+        self.started = true;
+        let bom;
+        (bom, self.encoding) = detect(head, self.encoding);
+        self.binary = is_binary(head, self.encoding);
+        self.reader.consume(bom);
+        Ok(bom)
+    }
+
+    // A newline is the code unit 0x000A, but the byte 0x0A can also be half of another char.
+    // `raw` may already hold the start of the line, from before the file grew.
+    fn read_utf16_line(&mut self) -> io::Result<()> {
+        loop {
+            let len = self.raw.len();
+            if self.raw.last() == Some(&b'\n') {
+                match self.encoding {
+                    // the newline has to be the low, first byte of its unit
+                    Encoding::Utf16Le if !len.is_multiple_of(2) => {
+                        let mut high = [0];
+                        let read = self.reader.read(&mut high)?;
+                        self.raw.extend_from_slice(&high[..read]);
+                        if read == 0 || high[0] == 0 {
+                            return Ok(());
+                        }
+                    }
+                    // the newline has to be the low, second byte after a zero
+                    Encoding::Utf16Be if len.is_multiple_of(2) && self.raw[len - 2] == 0 => {
+                        return Ok(())
+                    }
+                    _ => {}
+                }
+            }
+            if self.reader.read_until(b'\n', &mut self.raw)? == 0 {
+                return Ok(());
+            }
+            if self.raw.last() != Some(&b'\n') {
+                return Ok(()); // the end of the file
+            }
+        }
+    }
+}
+
+// The length of the byte order mark at the start of `head`, and the encoding to read the
+// rest in.
+fn detect(head: &[u8], wanted: Encoding) -> (usize, Encoding) {
+    let (bom, found) = match head {
+        [0xEF, 0xBB, 0xBF, ..] => (3, Encoding::Utf8),
+        [0xFF, 0xFE, ..] => (2, Encoding::Utf16Le),
+        [0xFE, 0xFF, ..] => (2, Encoding::Utf16Be),
+        _ => (0, wanted),
+    };
+    match (wanted, found) {
+        (Encoding::Auto, Encoding::Auto) => (0, Encoding::Utf8),
+        (Encoding::Auto, found) => (bom, found),
+        (wanted, found) if wanted == found => (bom, found),
+        // the mark doesn't belong to the encoding we were told to use, so it's text
+        (wanted, _) => (0, wanted),
+    }
+}
+
+/// How many bytes of a file in `encoding` the decoded `text` came from, which turns an
+/// offset in a decoded line into one in the file. Invalid UTF-8 that became U+FFFD is
+/// counted as the three bytes of that char, how long it was is lost.
+pub(crate) fn encoded_len(text: &str, encoding: Encoding) -> usize {
+    match encoding {
+        Encoding::Utf16Le | Encoding::Utf16Be => 2 * text.encode_utf16().count(),
+        Encoding::Latin1 => text.chars().count(),
+        Encoding::Auto | Encoding::Utf8 => text.len(),
+    }
+}
+
+// UTF-16 text is full of NUL bytes, anything else isn't.
+fn is_binary(bytes: &[u8], encoding: Encoding) -> bool {
+    !matches!(encoding, Encoding::Utf16Le | Encoding::Utf16Be) && bytes.contains(&0)
+}
+
+/// A whole file, decoded strictly so it can be written back the same way once changed.
+pub(crate) struct Decoded {
+    pub(crate) text: String,
+    encoding: Encoding,
+    bom: Vec<u8>,
+}
+
+/// Why a file can't be turned into text and back without losing something.
+#[derive(Debug, PartialEq)]
+pub(crate) enum Undecodable {
+    Binary,
+    Invalid(Encoding),
+}
+
+impl fmt::Display for Undecodable {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Undecodable::Binary => write!(f, "it is a binary file"),
+            Undecodable::Invalid(encoding) => write!(f, "it is not valid {}", encoding),
+        }
+    }
+}
+
+impl Decoded {
+    /// Decode `bytes` without replacing anything: a byte that is not valid in the encoding
+    /// or, unless `binary` is `Text`, a NUL byte, means the file can't be decoded.
+    pub(crate) fn new(
+        bytes: &[u8],
+        encoding: Encoding,
+        binary: BinaryMode,
+    ) -> Result<Decoded, Undecodable> {
+        let (bom, encoding) = detect(bytes, encoding);
+        let (bom, rest) = bytes.split_at(bom);
+        if binary != BinaryMode::Text && is_binary(rest, encoding) {
+            return Err(Undecodable::Binary);
+        }
+        let text = match encoding {
+            Encoding::Latin1 => Some(rest.iter().map(|&b| char::from(b)).collect()),
+            Encoding::Utf16Le | Encoding::Utf16Be if rest.len().is_multiple_of(2) => {
+                let units = rest.chunks(2).map(|pair| match encoding {
+                    Encoding::Utf16Le => u16::from_le_bytes([pair[0], pair[1]]),
+                    _ => u16::from_be_bytes([pair[0], pair[1]]),
+                });
+                char::decode_utf16(units).collect::<Result<_, _>>().ok()
+            }
+            Encoding::Utf16Le | Encoding::Utf16Be => None,
+            _ => String::from_utf8(rest.to_vec()).ok(),
+        };
+        Ok(Decoded {
+            text: text.ok_or(Undecodable::Invalid(encoding))?,
+            encoding,
+            bom: bom.to_vec(),
+        })
+    }
+
+    /// `text` in the encoding of the file, with its byte order mark. `None` if a char has
+    /// no place in the encoding, like '€' in Latin-1.
+    pub(crate) fn encode(&self, text: &str) -> Option<Vec<u8>> {
+        let mut bytes = self.bom.clone();
+        match self.encoding {
+            Encoding::Latin1 => {
+                for c in text.chars() {
+                    bytes.push(u8::try_from(c).ok()?);
+                }
+            }
+            Encoding::Utf16Le => bytes.extend(text.encode_utf16().flat_map(u16::to_le_bytes)),
+            Encoding::Utf16Be => bytes.extend(text.encode_utf16().flat_map(u16::to_be_bytes)),
+            _ => bytes.extend_from_slice(text.as_bytes()),
+        }
+        Some(bytes)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn read_lines(bytes: &[u8], encoding: Encoding) -> (Vec<(String, usize)>, bool) {
+        let mut reader = LineReader::new(bytes, encoding);
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        loop {
+            let read = reader.read_line(&mut line).unwrap();
+            if read == 0 {
+                break;
+            }
+            lines.push((line.clone(), read));
+        }
+        (lines, reader.is_binary())
+    }
+
+    fn utf16(text: &str, little_endian: bool) -> Vec<u8> {
+        let mut bytes = if little_endian {
+            vec![0xFF, 0xFE]
+        } else {
+            vec![0xFE, 0xFF]
+        };
+        for unit in text.encode_utf16() {
+            if little_endian {
+                bytes.extend(unit.to_le_bytes());
+            } else {
+                bytes.extend(unit.to_be_bytes());
+            }
+        }
+        bytes
+    }
+
+    #[test]
+    fn utf16_with_bom() {
+        // 'ਊ' is U+0A0A, both of its bytes look like a newline
+        let text = "How public,\nlike a ਊ frog\n";
+        for little_endian in [true, false] {
+            let (lines, binary) = read_lines(&utf16(text, little_endian), Encoding::Auto);
+            assert_eq!(
+                vec![
+                    ("How public,\n".to_string(), 2 + 24),
+                    ("like a ਊ frog\n".to_string(), 28)
+                ],
+                lines
+            );
+            assert!(!binary);
+        }
+    }
+
+    #[test]
+    fn latin1_and_lossy_utf8() {
+        let bytes = b"caf\xe9\nna\xefve\n";
+        let (lines, _) = read_lines(bytes, Encoding::Latin1);
+        assert_eq!("café\n", lines[0].0);
+        assert_eq!("naïve\n", lines[1].0);
+        let (lines, _) = read_lines(bytes, Encoding::Auto);
+        assert_eq!(("caf\u{FFFD}\n".to_string(), 5), lines[0]);
+    }
+
+    #[test]
+    fn nul_bytes_mean_binary() {
+        assert!(read_lines(b"ELF\0\x01\x02frog\n", Encoding::Auto).1);
+        assert!(!read_lines(b"frog\n", Encoding::Auto).1);
+        // a UTF-8 mark is skipped and not part of the text
+        let mut reader = LineReader::new(&b"\xEF\xBB\xBFfrog\nfrog\n"[..], Encoding::Auto);
+        let mut line = String::new();
+        assert_eq!(8, reader.read_line(&mut line).unwrap());
+        assert_eq!(("frog\n", 3), (&line[..], reader.line_offset()));
+        assert_eq!(5, reader.read_line(&mut line).unwrap());
+        assert_eq!(8, reader.line_offset());
+    }
+
+    #[test]
+    fn unfinished_lines_are_held_back() {
+        // a file that grows one byte at a time, so every line is cut everywhere once
+        let bytes = utf16("a ਊ frog\nmore\n", true);
+        let mut reader = LineReader::new(io::Cursor::new(Vec::new()), Encoding::Auto);
+        let mut lines = Vec::new();
+        let mut line = String::new();
+        for &byte in &bytes {
+            let cursor = reader.get_mut();
+            let position = cursor.position();
+            cursor.get_mut().push(byte);
+            cursor.set_position(position);
+            loop {
+                let read = reader.read_finished_line(&mut line).unwrap();
+                if read == 0 {
+                    break;
+                }
+                lines.push((line.clone(), read));
+            }
+        }
+        assert_eq!(
+            vec![
+                ("a ਊ frog\n".to_string(), 2 + 18),
+                ("more\n".to_string(), 10)
+            ],
+            lines
+        );
+    }
+
+    #[test]
+    fn strict_round_trip() {
+        let decoded = |bytes: &[u8], encoding| Decoded::new(bytes, encoding, BinaryMode::Quiet);
+        let le = decoded(&utf16("café\n", true), Encoding::Auto).unwrap();
+        assert_eq!("café\n", le.text);
+        assert_eq!(Some(utf16("cafe\n", true)), le.encode("cafe\n"));
+        let latin1 = decoded(b"caf\xe9\n", Encoding::Latin1).unwrap();
+        assert_eq!(Some(b"caf\xe9!\n".to_vec()), latin1.encode("café!\n"));
+        assert_eq!(None, latin1.encode("5 €\n"));
+        assert_eq!(
+            Some(Undecodable::Invalid(Encoding::Utf8)),
+            decoded(b"caf\xe9\n", Encoding::Auto).err()
+        );
+        assert_eq!(
+            Some(Undecodable::Binary),
+            decoded(b"ELF\0frog\n", Encoding::Auto).err()
+        );
+        let text = Decoded::new(b"ELF\0frog\n", Encoding::Auto, BinaryMode::Text);
+        assert_eq!("ELF\0frog\n", text.unwrap().text);
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Encoding::Utf16Le), "UTF-16LE".parse());
+        assert_eq!(Ok(Encoding::Latin1), "latin1".parse());
+        assert!("ebcdic".parse::<Encoding>().is_err());
+    }
+}
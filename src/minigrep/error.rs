@@ -0,0 +1,160 @@
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// Everything that can go wrong in minigrep, from parsing the arguments to writing the output.
+#[derive(Debug)]
+pub enum MinigrepError {
+    /// No query was given on the command line.
+    MissingQuery,
+    /// The command line could not be parsed, the message says why.
+    Usage(String),
+    /// The query is not a valid regular expression.
+    InvalidPattern(regex::Error),
+    NotFound {
+        path: PathBuf,
+    },
+    /// A file that has to be UTF-8 isn't, like a `-f` pattern file. Searched files are
+    /// decoded instead, see `Encoding`.
+    InvalidUtf8 {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Any other error while reading `path`.
+    Io {
+        path: PathBuf,
+        source: io::Error,
+    },
+    /// Walking a directory failed, e.g. because of a bad `--include` glob.
+    Walk(ignore::Error),
+    /// A config file or `MINIGREP_*` variable has a setting we don't understand. `origin`
+    /// is the file or the variable.
+    Settings {
+        origin: String,
+        message: String,
+    },
+    /// Writing the results failed, e.g. because the pipe we print into was closed.
+    Output(io::Error),
+    /// Some files could not be searched, but we went on with the rest. The errors were
+    /// printed as they came up, `first` is the first of them and the source of this one.
+    Incomplete {
+        failed: usize,
+        first: Box<MinigrepError>,
+    },
+}
+
+impl MinigrepError {
+    /// Sort an error that happened while reading `path` into the right variant.
+    pub(crate) fn read(path: &Path, source: io::Error) -> MinigrepError {
+        let path = path.to_path_buf();
+        match source.kind() {
+            io::ErrorKind::NotFound => MinigrepError::NotFound { path },
+            io::ErrorKind::InvalidData => MinigrepError::InvalidUtf8 { path, source },
+            _ => MinigrepError::Io { path, source },
+        }
+    }
+
+    /// The file the error is about, if there is one.
+    pub fn path(&self) -> Option<&Path> {
+        match self {
+            MinigrepError::NotFound { path }
+            | MinigrepError::InvalidUtf8 { path, .. }
+            | MinigrepError::Io { path, .. } => Some(path),
+            _ => None,
+        }
+    }
+}
+
+impl fmt::Display for MinigrepError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            MinigrepError::MissingQuery => write!(f, "Didn't get a query string"),
+            MinigrepError::Usage(message) => write!(f, "{}", message),
+            MinigrepError::InvalidPattern(_) => write!(f, "invalid regular expression"),
+            MinigrepError::NotFound { path } => {
+                write!(f, "{}: No such file or directory", path.display())
+            }
+            MinigrepError::InvalidUtf8 { path, .. } => {
+                write!(f, "{}: file is not valid UTF-8", path.display())
+            }
+            MinigrepError::Io { path, source } => write!(f, "{}: {}", path.display(), source),
+            MinigrepError::Walk(e) => write!(f, "{}", e),
+            MinigrepError::Settings { origin, message } => write!(f, "{}: {}", origin, message),
+            MinigrepError::Output(_) => write!(f, "could not write the results"),
+            MinigrepError::Incomplete { failed: 1, .. } => {
+                write!(f, "1 file could not be searched")
+            }
+            MinigrepError::Incomplete { failed, .. } => {
+                write!(f, "{} files could not be searched", failed)
+            }
+        }
+    }
+}
+
+impl Error for MinigrepError {
+    fn source(&self) -> Option<&(dyn Error + 'static)> {
+        match self {
+            MinigrepError::InvalidPattern(e) => Some(e),
+            MinigrepError::InvalidUtf8 { source, .. } | MinigrepError::Io { source, .. } => {
+                Some(source)
+            }
+            MinigrepError::Walk(e) => Some(e),
+            MinigrepError::Output(e) => Some(e),
+            MinigrepError::Incomplete { first, .. } => Some(&**first),
+            MinigrepError::MissingQuery
+            | MinigrepError::Usage(_)
+            | MinigrepError::NotFound { .. }
+            | MinigrepError::Settings { .. } => None,
+        }
+    }
+}
+
+impl From<regex::Error> for MinigrepError {
+    fn from(e: regex::Error) -> MinigrepError {
+        MinigrepError::InvalidPattern(e)
+    }
+}
+
+impl From<ignore::Error> for MinigrepError {
+    fn from(e: ignore::Error) -> MinigrepError {
+        MinigrepError::Walk(e)
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn read_errors_keep_the_path() {
+        let path = Path::new("poem.txt");
+        let error = MinigrepError::read(path, io::Error::from(io::ErrorKind::NotFound));
+        assert!(matches!(error, MinigrepError::NotFound { .. }));
+        assert_eq!(Some(path), error.path());
+        assert_eq!("poem.txt: No such file or directory", error.to_string());
+
+        let source = io::Error::new(io::ErrorKind::InvalidData, "bad byte");
+        let error = MinigrepError::read(path, source);
+        assert!(matches!(error, MinigrepError::InvalidUtf8 { .. }));
+        assert_eq!("bad byte", error.source().unwrap().to_string());
+    }
+
+    #[test]
+    fn incomplete_runs_chain_to_the_first_failure() {
+        let first = MinigrepError::read(Path::new("poem.txt"), io::ErrorKind::NotFound.into());
+        let error = MinigrepError::Incomplete {
+            failed: 2,
+            first: Box::new(first),
+        };
+        let source = error.source().unwrap().downcast_ref::<MinigrepError>();
+        assert!(matches!(source, Some(MinigrepError::NotFound { .. })));
+    }
+
+    #[test]
+    fn pattern_errors_chain_to_the_regex_error() {
+        let error = MinigrepError::from(regex::Regex::new("(").unwrap_err());
+        assert_eq!("invalid regular expression", error.to_string());
+        assert!(error.source().unwrap().is::<regex::Error>());
+    }
+}
@@ -0,0 +1,280 @@
+use caseless::Caseless;
+use std::ops::Range;
+use std::str::FromStr;
+use unicode_normalization::char::{
+    canonical_combining_class, compose, decompose_canonical, decompose_compatible,
+};
+
+/// Which strings count as the same text even though they are made of different chars.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Normalization {
+    /// Compare the chars as they are.
+    #[default]
+    None,
+    /// Precomposed and decomposed accents are the same, 'é' matches "e\u{301}".
+    Nfc,
+    /// Like `Nfc`, and compatibility chars match their plain forms too, 'ﬁ' matches "fi".
+    Nfkc,
+}
+
+impl FromStr for Normalization {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<Normalization, String> {
+        match s.to_ascii_lowercase().as_str() {
+            "none" => Ok(Normalization::None),
+            "nfc" => Ok(Normalization::Nfc),
+            "nfkc" => Ok(Normalization::Nfkc),
+            _ => Err(format!(
+                "invalid normalization '{}', expected none, nfc or nfkc",
+                s
+            )),
+        }
+    }
+}
+
+/// How the query and every line get folded before they are compared.
+///
+/// Folding the case uses the full Unicode case folding, so 'ß' matches "SS" and 'ﬀ' matches
+/// "FF", which `to_lowercase` gets wrong. The Turkish dotted and dotless i are folded the
+/// default (not Turkish) way: 'İ' matches "i\u{307}", and 'ı' only matches itself.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct Fold {
+    pub ignore_case: bool,
+    pub normalization: Normalization,
+}
+
+impl Fold {
+    /// A fold that only ignores the case, if asked to.
+    pub fn case(ignore_case: bool) -> Fold {
+        Fold {
+            ignore_case,
+            ..Fold::default()
+        }
+    }
+
+    /// Whether folding leaves every string as it is.
+    pub fn is_identity(&self) -> bool {
+        !self.ignore_case && self.normalization == Normalization::None
+    }
+
+    /// Fold a whole string.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::{Fold, Normalization};
+    ///
+    /// let fold = Fold { ignore_case: true, normalization: Normalization::Nfc };
+    /// assert_eq!(fold.apply("Straße"), fold.apply("STRASSE"));
+    /// assert_eq!(fold.apply("Café"), fold.apply("cafe\u{301}"));
+    /// ```
+    pub fn apply(&self, s: &str) -> String {
+        let mut chars = Vec::with_capacity(s.len());
+        self.fold_into(s, &mut chars);
+        chars.into_iter().map(|(c, _)| c).collect()
+    }
+
+    // Fold `s` into `out`, every char with the byte index of the char of `s` it came from.
+    //
+    // A decomposed char can be folded without looking at its neighbours, and folding can
+    // compose again (e.g. 'ǅ'), so we decompose once more afterwards. Then the combining marks
+    // get put in canonical order and composed, so a regex sees 'é' as one char however it was
+    // written.
+    fn fold_into(&self, s: &str, out: &mut Vec<(char, usize)>) {
+        out.clear();
+        let decompose = |c: char, push: &mut dyn FnMut(char)| match self.normalization {
+            Normalization::None => push(c),
+            Normalization::Nfc => decompose_canonical(c, push),
+            Normalization::Nfkc => decompose_compatible(c, push),
+        };
+        for (i, c) in s.char_indices() {
+            decompose(c, &mut |d| {
+                if self.ignore_case {
+                    for folded in std::iter::once(d).default_case_fold() {
+                        decompose(folded, &mut |e| out.push((e, i)));
+                    }
+                } else {
+                    out.push((d, i));
+                }
+            });
+        }
+        if self.normalization != Normalization::None {
+            reorder(out);
+            compose_all(out);
+        }
+    }
+}
+
+// Sort every run of combining marks by their combining class, which keeps marks of the same
+// class in order. The marks of a run that moved all count as part of its first char, so the
+// byte indexes stay in order.
+fn reorder(chars: &mut [(char, usize)]) {
+    let mut start = 0;
+    while start < chars.len() {
+        let len = chars[start..]
+            .iter()
+            .take_while(|(c, _)| canonical_combining_class(*c) != 0)
+            .count();
+        let run = &mut chars[start..start + len];
+        if run
+            .windows(2)
+            .any(|pair| canonical_combining_class(pair[0].0) > canonical_combining_class(pair[1].0))
+        {
+            let first = run[0].1;
+            run.sort_by_key(|(c, _)| canonical_combining_class(*c));
+            run.iter_mut().for_each(|(_, i)| *i = first);
+        }
+        start += len.max(1);
+    }
+}
+
+// The canonical composition of decomposed, ordered chars: a mark joins the starter before it
+// unless a mark of the same or a higher class (or another starter) is in between. Composed in
+// place, the chars only ever get fewer.
+fn compose_all(chars: &mut Vec<(char, usize)>) {
+    let mut starter: Option<usize> = None;
+    // the class of the last char since the starter that stayed on its own
+    let mut last_class = None;
+    let mut len = 0;
+    for next in 0..chars.len() {
+        let (c, i) = chars[next];
+        let class = canonical_combining_class(c);
+        if let Some(s) = starter {
+            let blocked = last_class.is_some_and(|last| last == 0 || last >= class);
+            if let Some(joined) = compose(chars[s].0, c).filter(|_| !blocked) {
+                chars[s].0 = joined;
+                continue;
+            }
+        }
+        if class == 0 {
+            starter = Some(len);
+            last_class = None;
+        } else {
+            last_class = Some(class);
+        }
+        chars[len] = (c, i);
+        len += 1;
+    }
+    chars.truncate(len);
+}
+
+/// Search the folded `line` with `find` and map the spans back to the original line.
+///
+/// Folding can change how many bytes a char takes (e.g. 'ß' becomes "ss"), so we remember
+/// for every byte of the folded line where its char started in the original one. A hit that
+/// starts or ends inside what a char folded into covers that whole char. Hits that end up
+/// overlapping that way, like the two hits of "s" in 'ß', become one, with the id of the
+/// first, so the spans stay sorted and disjoint.
+pub(crate) fn folded<F>(line: &str, fold: Fold, find: F) -> Vec<(usize, Range<usize>)>
+where
+    F: FnOnce(&str) -> Vec<(usize, Range<usize>)>,
+{
+    let mut chars = Vec::with_capacity(line.len());
+    fold.fold_into(line, &mut chars);
+    let mut folded = String::with_capacity(line.len());
+    let mut origin = Vec::with_capacity(line.len() + 1);
+    for (c, i) in chars {
+        folded.push(c);
+        origin.resize(folded.len(), i);
+    }
+    origin.push(line.len());
+
+    let mut hits: Vec<(usize, Range<usize>)> = Vec::new();
+    for (id, Range { start, end }) in find(&folded) {
+        // the match ended in the middle of what a char folded into, so it covers the
+        // whole char
+        let end = if end > 0 {
+            origin[end..]
+                .iter()
+                .find(|&&i| i != origin[end - 1])
+                .copied()
+                .unwrap_or(line.len())
+        } else {
+            origin[end]
+        };
+        let start = origin[start];
+        match hits.last_mut() {
+            Some((_, last)) if start < last.end => last.end = last.end.max(end),
+            _ => hits.push((id, start..end)),
+        }
+    }
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fold(ignore_case: bool, normalization: Normalization) -> Fold {
+        Fold {
+            ignore_case,
+            normalization,
+        }
+    }
+
+    #[test]
+    fn full_case_folding() {
+        let fold = Fold::case(true);
+        assert_eq!("strasse", fold.apply("Straße"));
+        assert_eq!("strasse", fold.apply("STRAẞE"));
+        assert_eq!("i\u{307}stanbul", fold.apply("İSTANBUL"));
+        // dotless i stays dotless, and the plain I folds to i
+        assert_eq!("ı", fold.apply("ı"));
+        assert_eq!("i", fold.apply("I"));
+    }
+
+    #[test]
+    fn normalization_forms() {
+        let precomposed = "café";
+        let decomposed = "cafe\u{301}";
+        assert_ne!(Fold::case(true).apply(precomposed), decomposed);
+        let nfc = fold(false, Normalization::Nfc);
+        assert_eq!(nfc.apply(precomposed), nfc.apply(decomposed));
+        // 'ﬁ' is only compatible with "fi", not canonically equal
+        assert_ne!(nfc.apply("ﬁ"), nfc.apply("fi"));
+        let nfkc = fold(false, Normalization::Nfkc);
+        assert_eq!(nfkc.apply("ﬁ"), nfkc.apply("fi"));
+        assert_eq!(fold(true, Normalization::Nfkc).apply("ＦＲＯＧ"), "frog");
+    }
+
+    #[test]
+    fn spans_map_back() {
+        let line = "Große Straße";
+        let fold = Fold::case(true);
+        let find = |folded: &str| {
+            folded
+                .match_indices("strasse")
+                .map(|(i, m)| (0, i..i + m.len()))
+                .collect()
+        };
+        assert_eq!(vec![(0, 7..14)], folded(line, fold, find));
+        // half of a folded 'ß' still counts as the whole char
+        let find = |folded: &str| {
+            folded
+                .match_indices("os")
+                .map(|(i, m)| (0, i..i + m.len()))
+                .collect()
+        };
+        assert_eq!(vec![(0, 2..5)], folded(line, fold, find));
+    }
+
+    #[test]
+    fn combining_marks_are_composed() {
+        let nfc = fold(true, Normalization::Nfc);
+        // the marks in either order, the dot below (220) sorts before the acute (230)
+        assert_eq!("ấ", nfc.apply("A\u{302}\u{301}"));
+        assert_eq!(nfc.apply("ạ\u{302}"), nfc.apply("a\u{302}\u{323}"));
+        // a '.' takes the letter and its accent, and maps back to both
+        let regex = regex::Regex::new(&nfc.apply("caf.!")).unwrap();
+        let find = |folded: &str| regex.find_iter(folded).map(|m| (0, m.range())).collect();
+        let line = "Cafe\u{301}! cafe\u{302}\u{323}!";
+        assert_eq!(vec![(0, 0..7), (0, 8..17)], folded(line, nfc, find));
+    }
+
+    #[test]
+    fn parse() {
+        assert_eq!(Ok(Normalization::Nfkc), "NFKC".parse());
+        assert!("nfd".parse::<Normalization>().is_err());
+    }
+}
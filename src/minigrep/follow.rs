@@ -0,0 +1,224 @@
+use super::decode::{BinaryMode, Encoding, LineReader};
+use super::{MinigrepError, OutputMode, Search, Sink};
+use std::fs::{self, File, Metadata};
+use std::io::{self, BufReader, Seek};
+use std::path::{Path, PathBuf};
+
+/// Reads a file that keeps growing, like `tail -F`.
+///
+/// Every `poll` reads the lines appended since the last one. A line is only searched once
+/// its newline has arrived, so a line that is still being written isn't cut in two. When
+/// the file gets truncated or another file is moved to its path (log rotation), we start
+/// over at the beginning of what is there now.
+///
+/// The lines are decoded and checked for binary content like `Search::file` does it. Once
+/// a binary file has been skipped or reported as matching, we stop reading it until it gets
+/// replaced.
+pub(crate) struct Follower {
+    path: PathBuf,
+    encoding: Encoding,
+    lines: LineReader<BufReader<File>>,
+    identity: Option<(u64, u64)>,
+    line_number: usize,
+    // a binary file there is nothing more to say about
+    done: bool,
+}
+
+impl Follower {
+    pub(crate) fn open(path: &Path, encoding: Encoding) -> Result<Follower, MinigrepError> {
+        let file = File::open(path).map_err(|e| MinigrepError::read(path, e))?;
+        let metadata = file.metadata().map_err(|e| MinigrepError::read(path, e))?;
+        Ok(Follower {
+            path: path.to_path_buf(),
+            encoding,
+            lines: LineReader::new(BufReader::new(file), encoding),
+            identity: identity_of(&metadata),
+            line_number: 0,
+            done: false,
+        })
+    }
+
+    /// Search the lines that were added since the last poll.
+    pub(crate) fn poll<S: Sink + ?Sized>(
+        &mut self,
+        search: &Search,
+        sink: &mut S,
+    ) -> Result<(), MinigrepError> {
+        // whatever got written before a rotation still belongs to the old file
+        self.read(search, sink)?;
+        let metadata = match fs::metadata(&self.path) {
+            Ok(metadata) => metadata,
+            // moved away and the new file isn't there yet
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(()),
+            Err(e) => return Err(MinigrepError::read(&self.path, e)),
+        };
+        if identity_of(&metadata) != self.identity {
+            eprintln!(
+                "minigrep: {} has been replaced, following the new file",
+                self.path.display()
+            );
+            *self = Follower::open(&self.path, self.encoding)?;
+        } else if metadata.len() < self.position()? {
+            eprintln!("minigrep: {} has been truncated", self.path.display());
+            // the new start may have another byte order mark, so we start from scratch
+            *self = Follower::open(&self.path, self.encoding)?;
+        } else {
+            return Ok(());
+        }
+        self.read(search, sink)
+    }
+
+    fn read<S: Sink + ?Sized>(
+        &mut self,
+        search: &Search,
+        sink: &mut S,
+    ) -> Result<(), MinigrepError> {
+        let mut line = String::new();
+        while !self.done {
+            let read = self
+                .lines
+                .read_finished_line(&mut line)
+                .map_err(|e| MinigrepError::read(&self.path, e))?;
+            if read == 0 {
+                // the end for now, the rest of the line comes with a later poll
+                return Ok(());
+            }
+            let binary = self.lines.is_binary() && search.binary != BinaryMode::Text;
+            if binary && search.binary == BinaryMode::Skip {
+                self.done = true;
+                return Ok(());
+            }
+            self.line_number += 1;
+            // the sink may have seen other files since the last poll
+            let encoding = self.lines.encoding();
+            sink.decoded_from(encoding.map_err(|e| MinigrepError::read(&self.path, e))?);
+            let text = line.trim_end_matches(['\n', '\r']);
+            let selected = search.pattern.is_match(text) != search.invert;
+            if selected && binary && search.mode == OutputMode::Lines {
+                self.done = true;
+                return sink.binary_match().map_err(MinigrepError::Output);
+            }
+            search
+                .line(
+                    self.line_number,
+                    self.lines.line_offset(),
+                    text,
+                    selected,
+                    sink,
+                )
+                .map_err(MinigrepError::Output)?;
+        }
+        Ok(())
+    }
+
+    // How far we have read, including the unfinished line.
+    fn position(&mut self) -> Result<u64, MinigrepError> {
+        self.lines
+            .get_mut()
+            .stream_position()
+            .map_err(|e| MinigrepError::read(&self.path, e))
+    }
+}
+
+// Device and inode, which change when another file takes over the path. Elsewhere we can
+// only notice truncation.
+#[cfg(unix)]
+fn identity_of(metadata: &Metadata) -> Option<(u64, u64)> {
+    use std::os::unix::fs::MetadataExt;
+    Some((metadata.dev(), metadata.ino()))
+}
+
+#[cfg(not(unix))]
+fn identity_of(_: &Metadata) -> Option<(u64, u64)> {
+    None
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minigrep::tests::search;
+    use crate::minigrep::{Pattern, PrintOptions, Printer};
+    use std::io::Write;
+
+    fn append(path: &Path, text: &str) {
+        let mut file = fs::OpenOptions::new().append(true).open(path).unwrap();
+        file.write_all(text.as_bytes()).unwrap();
+    }
+
+    #[test]
+    fn growing_and_rotated_files() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        fs::write(&path, "a frog\nno toad\n").unwrap();
+        let search = search(Pattern::literal("frog", true));
+        let options = PrintOptions {
+            line_numbers: true,
+            ..PrintOptions::default()
+        };
+        let mut printer = Printer::new(Vec::new(), options);
+        let mut follower = Follower::open(&path, Encoding::Auto).unwrap();
+        follower.poll(&search, &mut printer).unwrap();
+
+        // half a line waits for the rest
+        append(&path, "a frog being wr");
+        follower.poll(&search, &mut printer).unwrap();
+        append(&path, "itten\n");
+        follower.poll(&search, &mut printer).unwrap();
+
+        // truncated, like logrotate's copytruncate
+        fs::write(&path, "frog\n").unwrap();
+        follower.poll(&search, &mut printer).unwrap();
+
+        // moved away and replaced by a new file
+        append(&path, "last frog\n");
+        let new = dir.path().join("app.log.new");
+        fs::write(&new, "frog in a new file\n").unwrap();
+        fs::rename(&new, &path).unwrap();
+        follower.poll(&search, &mut printer).unwrap();
+
+        let out = String::from_utf8(printer.into_inner()).unwrap();
+        assert_eq!(
+            "1:a frog\n3:a frog being written\n1:frog\n2:last frog\n1:frog in a new file\n",
+            out
+        );
+    }
+
+    #[test]
+    fn decoded_like_a_search() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("app.log");
+        let utf16 =
+            |text: &str| -> Vec<u8> { text.encode_utf16().flat_map(u16::to_le_bytes).collect() };
+        let mut bytes = vec![0xFF, 0xFE];
+        bytes.extend(utf16("a frog\n"));
+        fs::write(&path, &bytes).unwrap();
+        let mut search = search(Pattern::literal("frog", true));
+        let options = PrintOptions {
+            byte_offset: true,
+            ..PrintOptions::default()
+        };
+        let mut printer = Printer::new(Vec::new(), options);
+        printer.start_file("app.log").unwrap();
+        let mut follower = Follower::open(&path, search.encoding).unwrap();
+        follower.poll(&search, &mut printer).unwrap();
+        // the offsets count the bytes of the file, the first line starts after the mark
+        let mut file = fs::OpenOptions::new().append(true).open(&path).unwrap();
+        file.write_all(&utf16("café frog\n")).unwrap();
+        follower.poll(&search, &mut printer).unwrap();
+
+        // a binary file matches once and then we leave it alone
+        fs::write(&path, "bin\0ary\nfrog\nfrog\n").unwrap();
+        follower = Follower::open(&path, search.encoding).unwrap();
+        follower.poll(&search, &mut printer).unwrap();
+        append(&path, "frog\n");
+        follower.poll(&search, &mut printer).unwrap();
+
+        // or not at all when binary files are skipped
+        search.binary = BinaryMode::Skip;
+        follower = Follower::open(&path, search.encoding).unwrap();
+        follower.poll(&search, &mut printer).unwrap();
+
+        let out = String::from_utf8(printer.into_inner()).unwrap();
+        assert_eq!("2:a frog\n16:café frog\nBinary file app.log matches\n", out);
+    }
+}
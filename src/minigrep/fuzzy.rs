@@ -0,0 +1,109 @@
+use std::ops::Range;
+
+/// Find the substrings of `text` that are at most `max` edits (insertions, deletions or
+/// substitutions of a char) away from `query`, as `(distance, byte span)`.
+///
+/// This is Sellers' algorithm: one column of the edit distance table per char of `text`,
+/// where a match may start anywhere. With Ukkonen's cut-off we only compute the rows that can
+/// still stay within `max`, so a line costs about `len * (max + 1)` steps instead of
+/// `len * query.len()`.
+///
+/// Hits that overlap are merged, keeping the closest (and then the longest) one.
+pub(crate) fn find(query: &[char], max: usize, text: &str) -> Vec<(usize, Range<usize>)> {
+    let m = query.len();
+    // cost[i] is the fewest edits to match query[..i] ending at the current position,
+    // start[i] is where in `text` that match starts
+    let mut cost: Vec<usize> = (0..=m).collect();
+    let mut start = vec![0; m + 1];
+    // rows above `valid` hold the current column, the rest are known to cost more than max
+    let mut valid = m;
+    let mut last = max.min(m);
+
+    let mut hits = Vec::new();
+    let mut best: Option<(usize, Range<usize>)> = None;
+    let mut report = |distance: usize, span: Range<usize>| match &best {
+        Some((d, b)) if span.start < b.end => {
+            // on a tie the longer hit wins, "café" is a better hit than "caf"
+            if distance <= *d {
+                best = Some((distance, span));
+            }
+        }
+        _ => {
+            hits.extend(best.replace((distance, span)));
+        }
+    };
+    if cost[m] <= max {
+        report(cost[m], 0..0);
+    }
+
+    for (pos, c) in text.char_indices() {
+        let end = pos + c.len_utf8();
+        let rows = (last + 1).min(m);
+        // the diagonal neighbour, from the previous column
+        let (mut diagonal, mut diagonal_start) = (cost[0], start[0]);
+        start[0] = end;
+        for i in 1..=rows {
+            let (left, left_start) = if i <= valid {
+                (cost[i], start[i])
+            } else {
+                (usize::MAX - 1, 0)
+            };
+            let substitute = diagonal + usize::from(query[i - 1] != c);
+            let (mut best_cost, mut best_start) = (substitute, diagonal_start);
+            // the text has an extra char
+            if left + 1 < best_cost {
+                (best_cost, best_start) = (left + 1, left_start);
+            }
+            // the text misses a char of the query
+            if cost[i - 1] + 1 < best_cost {
+                (best_cost, best_start) = (cost[i - 1] + 1, start[i - 1]);
+            }
+            (diagonal, diagonal_start) = (left, left_start);
+            (cost[i], start[i]) = (best_cost, best_start);
+        }
+        valid = rows;
+        last = rows;
+        while last > 0 && cost[last] > max {
+            last -= 1;
+        }
+        if last == m {
+            report(cost[m], start[m]..end);
+        }
+    }
+    hits.extend(best);
+    hits
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn fuzzy(query: &str, max: usize, text: &str) -> Vec<(usize, Range<usize>)> {
+        find(&query.chars().collect::<Vec<_>>(), max, text)
+    }
+
+    #[test]
+    fn exact_hits_have_no_distance() {
+        assert_eq!(
+            vec![(0, 2..6), (0, 10..14)],
+            fuzzy("frog", 1, "a frog, a frog")
+        );
+        assert!(fuzzy("frog", 0, "a frg").is_empty());
+    }
+
+    #[test]
+    fn typos() {
+        // a missing, a wrong and an extra letter
+        assert_eq!(vec![(1, 7..10)], fuzzy("frog", 1, "like a frg"));
+        assert_eq!(vec![(1, 7..11)], fuzzy("frog", 1, "like a frig"));
+        assert_eq!(vec![(0, 7..11)], fuzzy("frog", 1, "like a frogg"));
+        assert_eq!(vec![(2, 0..5)], fuzzy("nobody", 2, "nopdy"));
+        assert!(fuzzy("nobody", 1, "nopdy").is_empty());
+    }
+
+    #[test]
+    fn unicode_chars() {
+        // 'é' is one edit away from 'e', not two, even though it takes two bytes
+        assert_eq!(vec![(1, 0..5)], fuzzy("cafe", 1, "café!"));
+    }
+}
@@ -0,0 +1,385 @@
+use super::decode::{Encoding, LineReader};
+use super::{replace, walk, Fold, MinigrepError, WalkOptions};
+use std::collections::{BTreeMap, HashSet};
+use std::fs::{self, File};
+use std::io::{self, BufRead, BufReader};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::time::UNIX_EPOCH;
+
+/// The name of the index file, in the root of the indexed directory.
+pub const INDEX_FILE: &str = ".minigrep-index";
+
+// the first line of every index file, bumped whenever the format changes
+const HEADER: &str = "minigrep-index 1";
+
+/// Where a term occurs: the file (as index into `Index::files`), the line (from 1) and the
+/// position of the word within the line (from 0), which phrase queries need.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Hash)]
+struct Posting {
+    file: usize,
+    line: usize,
+    position: usize,
+}
+
+/// A file as it was when it got indexed, so we can tell when it changed.
+#[derive(Debug, Clone, PartialEq)]
+struct Stamp {
+    // relative to the root
+    path: PathBuf,
+    modified: (u64, u32),
+    len: u64,
+}
+
+/// An inverted index of a directory: for every word, the lines it occurs on.
+///
+/// Words are runs of letters and digits, compared case-insensitively. The index is stored as
+/// a plain text file in the root of the directory.
+#[derive(Debug)]
+pub struct Index {
+    root: PathBuf,
+    files: Vec<Stamp>,
+    postings: BTreeMap<String, Vec<Posting>>,
+}
+
+impl Index {
+    /// Read and index every file below `root` that `walk` lets through. Binary files are
+    /// remembered but not indexed.
+    pub fn build(root: &Path, walk: &WalkOptions) -> Result<Index, MinigrepError> {
+        let mut index = Index {
+            root: root.to_path_buf(),
+            files: Vec::new(),
+            postings: BTreeMap::new(),
+        };
+        for stamp in stamps(root, walk)? {
+            let path = root.join(&stamp.path);
+            let file = File::open(&path).map_err(|e| MinigrepError::read(&path, e))?;
+            let mut lines = LineReader::new(BufReader::new(file), Encoding::Auto);
+            let mut line = String::new();
+            let mut postings = Vec::new();
+            let mut line_number = 0;
+            while lines
+                .read_line(&mut line)
+                .map_err(|e| MinigrepError::read(&path, e))?
+                > 0
+            {
+                line_number += 1;
+                for (position, term) in terms(&line).into_iter().enumerate() {
+                    postings.push((term, line_number, position));
+                }
+            }
+            if !lines.is_binary() {
+                let file = index.files.len();
+                for (term, line, position) in postings {
+                    index.postings.entry(term).or_default().push(Posting {
+                        file,
+                        line,
+                        position,
+                    });
+                }
+            }
+            index.files.push(stamp);
+        }
+        Ok(index)
+    }
+
+    /// Load the index of `root`, building (and saving) it first if there is none yet or if
+    /// any file was added, removed or modified since it was built.
+    pub fn open(root: &Path, walk: &WalkOptions) -> Result<Index, MinigrepError> {
+        if let Some(index) = Index::load(root)? {
+            if index.files == stamps(root, walk)? {
+                return Ok(index);
+            }
+            eprintln!(
+                "minigrep: the index of {} is out of date, rebuilding it",
+                root.display()
+            );
+        }
+        let index = Index::build(root, walk)?;
+        index.save()?;
+        Ok(index)
+    }
+
+    /// Read the index file of `root`. A missing or unreadable index is `None`, it just has
+    /// to be built again.
+    pub fn load(root: &Path) -> Result<Option<Index>, MinigrepError> {
+        let path = root.join(INDEX_FILE);
+        let file = match File::open(&path) {
+            Ok(file) => file,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => return Ok(None),
+            Err(e) => return Err(MinigrepError::read(&path, e)),
+        };
+        let mut lines = BufReader::new(file).lines();
+        let mut next = || lines.next().and_then(Result::ok);
+        if next().as_deref() != Some(HEADER) {
+            return Ok(None);
+        }
+        let mut parse = || -> Option<Index> {
+            let mut index = Index {
+                root: root.to_path_buf(),
+                files: Vec::new(),
+                postings: BTreeMap::new(),
+            };
+            let count: usize = next()?.strip_prefix("files ")?.parse().ok()?;
+            for _ in 0..count {
+                let line = next()?;
+                let mut fields = line.splitn(4, ' ');
+                let secs = fields.next()?.parse().ok()?;
+                let nanos = fields.next()?.parse().ok()?;
+                let len = fields.next()?.parse().ok()?;
+                let path: String = serde_json::from_str(fields.next()?).ok()?;
+                index.files.push(Stamp {
+                    path: PathBuf::from(path),
+                    modified: (secs, nanos),
+                    len,
+                });
+            }
+            while let Some(line) = next() {
+                let mut fields = line.split(' ');
+                let term = fields.next()?.to_string();
+                let postings = fields
+                    .map(|posting| {
+                        let mut numbers = posting.split(':').map(|n| n.parse().ok());
+                        Some(Posting {
+                            file: numbers.next()??,
+                            line: numbers.next()??,
+                            position: numbers.next()??,
+                        })
+                    })
+                    .collect::<Option<Vec<_>>>()?;
+                index.postings.insert(term, postings);
+            }
+            Some(index)
+        };
+        Ok(parse())
+    }
+
+    /// Write the index into the index file of its root.
+    pub fn save(&self) -> Result<(), MinigrepError> {
+        let mut out = format!("{}\nfiles {}\n", HEADER, self.files.len());
+        for stamp in &self.files {
+            let path = serde_json::to_string(&stamp.path.to_string_lossy())
+                .expect("a string is always valid JSON");
+            out.push_str(&format!(
+                "{} {} {} {}\n",
+                stamp.modified.0, stamp.modified.1, stamp.len, path
+            ));
+        }
+        for (term, postings) in &self.postings {
+            out.push_str(term);
+            for posting in postings {
+                out.push_str(&format!(
+                    " {}:{}:{}",
+                    posting.file, posting.line, posting.position
+                ));
+            }
+            out.push('\n');
+        }
+        replace::write_atomically(&self.root.join(INDEX_FILE), out.as_bytes())
+    }
+
+    pub fn files(&self) -> usize {
+        self.files.len()
+    }
+
+    pub fn terms(&self) -> usize {
+        self.postings.len()
+    }
+
+    /// Find the lines that contain every word of `query`. Words in double quotes have to
+    /// appear as a phrase, in that order and right after each other.
+    ///
+    /// Returns the path of the file and the line number of every hit, in order.
+    ///
+    /// # Examples
+    ///
+    /// ```no_run
+    /// use rust_book::{Index, WalkOptions};
+    /// use std::path::Path;
+    ///
+    /// let index = Index::open(Path::new("poems"), &WalkOptions::default()).unwrap();
+    /// for (path, line) in index.query("\"pair of us\" tell") {
+    ///     println!("{}:{}", path.display(), line);
+    /// }
+    /// ```
+    pub fn query(&self, query: &str) -> Vec<(PathBuf, usize)> {
+        let mut clauses = Vec::new();
+        for (i, part) in query.split('"').enumerate() {
+            if i % 2 == 1 {
+                clauses.push(terms(part));
+            } else {
+                clauses.extend(terms(part).into_iter().map(|term| vec![term]));
+            }
+        }
+        clauses.retain(|clause| !clause.is_empty());
+
+        let mut hits: Option<HashSet<(usize, usize)>> = None;
+        for clause in &clauses {
+            let lines = self.phrase(clause);
+            hits = Some(match hits {
+                Some(hits) => hits.intersection(&lines).copied().collect(),
+                None => lines,
+            });
+        }
+        let mut hits: Vec<_> = hits.unwrap_or_default().into_iter().collect();
+        hits.sort_unstable();
+        hits.into_iter()
+            .map(|(file, line)| (self.root.join(&self.files[file].path), line))
+            .collect()
+    }
+
+    // the lines where the terms follow each other
+    fn phrase(&self, terms: &[String]) -> HashSet<(usize, usize)> {
+        let postings: Vec<&[Posting]> = terms
+            .iter()
+            .map(|term| self.postings.get(term).map_or(&[][..], Vec::as_slice))
+            .collect();
+        let following: Vec<HashSet<&Posting>> = postings[1..]
+            .iter()
+            .map(|postings| postings.iter().collect())
+            .collect();
+        postings[0]
+            .iter()
+            .filter(|first| {
+                following.iter().enumerate().all(|(i, postings)| {
+                    postings.contains(&Posting {
+                        position: first.position + i + 1,
+                        ..**first
+                    })
+                })
+            })
+            .map(|posting| (posting.file, posting.line))
+            .collect()
+    }
+}
+
+// Split a line into its words, case folded the same way `-i` does it.
+fn terms(line: &str) -> Vec<String> {
+    words(line).into_iter().map(|(_, term)| term).collect()
+}
+
+// The words of a line with their spans.
+fn words(line: &str) -> Vec<(Range<usize>, String)> {
+    let fold = Fold::case(true);
+    let mut words = Vec::new();
+    let mut start = None;
+    for (i, c) in line.char_indices().chain([(line.len(), ' ')]) {
+        match (start, c.is_alphanumeric()) {
+            (None, true) => start = Some(i),
+            (Some(s), false) => {
+                words.push((s..i, fold.apply(&line[s..i])));
+                start = None;
+            }
+            _ => {}
+        }
+    }
+    words
+}
+
+/// The spans of the words in `line` that occur in `query`, to highlight a hit.
+pub(crate) fn highlight(query: &str, line: &str) -> Vec<Range<usize>> {
+    let wanted: HashSet<String> = terms(query).into_iter().collect();
+    words(line)
+        .into_iter()
+        .filter(|(_, term)| wanted.contains(term))
+        .map(|(span, _)| span)
+        .collect()
+}
+
+// What the files below `root` look like right now, to compare against the index.
+fn stamps(root: &Path, walk: &WalkOptions) -> Result<Vec<Stamp>, MinigrepError> {
+    let mut stamps = Vec::new();
+    let found = walk::files(root, walk)?;
+    // what we can't read can't be indexed either, the rest still can
+    for e in found.errors {
+        eprintln!("minigrep: {}", e);
+    }
+    for path in found.files {
+        if path.file_name() == Some(INDEX_FILE.as_ref()) {
+            continue;
+        }
+        let metadata = fs::metadata(&path).map_err(|e| MinigrepError::read(&path, e))?;
+        let modified = metadata
+            .modified()
+            .ok()
+            .and_then(|time| time.duration_since(UNIX_EPOCH).ok())
+            .map_or((0, 0), |since| (since.as_secs(), since.subsec_nanos()));
+        stamps.push(Stamp {
+            path: path.strip_prefix(root).unwrap_or(&path).to_path_buf(),
+            modified,
+            len: metadata.len(),
+        });
+    }
+    Ok(stamps)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn corpus() -> tempfile::TempDir {
+        let temp = tempfile::tempdir().unwrap();
+        let dir = temp.path();
+        fs::create_dir_all(dir.join("poems")).unwrap();
+        fs::write(
+            dir.join("poems/nobody.txt"),
+            "I'm nobody! Who are you?\nThen there's a pair of us - don't tell!\nThey'd banish us, you know.\n",
+        )
+        .unwrap();
+        fs::write(
+            dir.join("frog.txt"),
+            "How public, like a Frog\nus and a pair\n",
+        )
+        .unwrap();
+        fs::write(dir.join("a.out"), b"\0us\n").unwrap();
+        temp
+    }
+
+    #[test]
+    fn words_and_phrases() {
+        let temp = corpus();
+        let dir = temp.path();
+        let index = Index::build(dir, &WalkOptions::default()).unwrap();
+        assert_eq!(3, index.files());
+        let nobody = dir.join("poems/nobody.txt");
+        let frog = dir.join("frog.txt");
+        assert_eq!(
+            vec![(frog.clone(), 2), (nobody.clone(), 2), (nobody.clone(), 3)],
+            index.query("US")
+        );
+        assert_eq!(vec![(nobody.clone(), 2)], index.query("\"pair of us\""));
+        assert_eq!(vec![(frog.clone(), 2)], index.query("pair and us"));
+        assert_eq!(vec![(frog, 1)], index.query("frog public"));
+        assert!(index.query("\"us pair\"").is_empty());
+        assert!(index.query("toad").is_empty());
+    }
+
+    #[test]
+    fn highlighted_words() {
+        let line = "Then there's a pair of us - don't tell!";
+        assert_eq!(vec![23..25, 34..38], highlight("TELL us", line));
+        // whole words only
+        assert!(highlight("the", line).is_empty());
+    }
+
+    #[test]
+    fn saved_and_rebuilt() {
+        let temp = corpus();
+        let dir = temp.path();
+        let walk = WalkOptions::default();
+        let index = Index::open(dir, &walk).unwrap();
+        let loaded = Index::load(dir).unwrap().unwrap();
+        assert_eq!(index.files, loaded.files);
+        assert_eq!(index.postings, loaded.postings);
+
+        // a file that grew is noticed even if the clock is too coarse for its mtime
+        fs::write(
+            dir.join("frog.txt"),
+            "How public, like a frog\nTo an admiring bog!\n",
+        )
+        .unwrap();
+        let index = Index::open(dir, &walk).unwrap();
+        assert_eq!(vec![(dir.join("frog.txt"), 2)], index.query("bog"));
+        assert_eq!(index.files, Index::load(dir).unwrap().unwrap().files);
+    }
+}
@@ -0,0 +1,205 @@
+use super::printer::Sink;
+use super::Stats;
+use serde_json::json;
+use std::io::{self, Write};
+use std::ops::Range;
+use std::time::Duration;
+
+/// Prints search results as JSON Lines, one object per event, for scripts to consume.
+///
+/// Every file starts with a `begin` event, every matching line is a `match` event and
+/// an `end` event tells how many lines of the file matched.
+/// Context lines are left out, scripts can read them from the file if they need them.
+pub struct JsonPrinter<W: Write> {
+    out: W,
+    path: String,
+}
+
+impl<W: Write> JsonPrinter<W> {
+    pub fn new(out: W) -> JsonPrinter<W> {
+        JsonPrinter {
+            out,
+            path: String::new(),
+        }
+    }
+
+    fn event(&mut self, event: serde_json::Value) -> io::Result<()> {
+        serde_json::to_writer(&mut self.out, &event)?;
+        writeln!(self.out)
+    }
+
+    fn match_event(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        submatches: Vec<serde_json::Value>,
+    ) -> io::Result<()> {
+        let event = json!({
+            "type": "match",
+            "data": {
+                "path": self.path,
+                "line_number": line_number,
+                "absolute_offset": offset,
+                "line": text,
+                "submatches": submatches,
+            }
+        });
+        self.event(event)
+    }
+}
+
+impl<W: Write> Sink for JsonPrinter<W> {
+    fn start_file(&mut self, path: &str) -> io::Result<()> {
+        self.path = path.to_string();
+        self.event(json!({"type": "begin", "data": {"path": path}}))
+    }
+
+    fn line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        spans: Option<&[Range<usize>]>,
+    ) -> io::Result<()> {
+        let Some(spans) = spans else {
+            return Ok(());
+        };
+        let submatches: Vec<_> = spans
+            .iter()
+            .map(|span| json!({"match": &text[span.clone()], "start": span.start, "end": span.end}))
+            .collect();
+        self.match_event(line_number, offset, text, submatches)
+    }
+
+    fn multi_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        hits: &[(usize, Range<usize>)],
+    ) -> io::Result<()> {
+        let submatches: Vec<_> = hits
+            .iter()
+            .map(|(pattern, span)| {
+                json!({
+                    "match": &text[span.clone()],
+                    "start": span.start,
+                    "end": span.end,
+                    "pattern": pattern,
+                })
+            })
+            .collect();
+        self.match_event(line_number, offset, text, submatches)
+    }
+
+    fn fuzzy_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        hits: &[(usize, Range<usize>)],
+    ) -> io::Result<()> {
+        let submatches: Vec<_> = hits
+            .iter()
+            .map(|(distance, span)| {
+                json!({
+                    "match": &text[span.clone()],
+                    "start": span.start,
+                    "end": span.end,
+                    "distance": distance,
+                })
+            })
+            .collect();
+        self.match_event(line_number, offset, text, submatches)
+    }
+
+    fn binary_match(&mut self) -> io::Result<()> {
+        self.event(json!({"type": "binary", "data": {"path": self.path}}))
+    }
+
+    fn end_file(&mut self, matched_lines: usize) -> io::Result<()> {
+        let event = json!({
+            "type": "end",
+            "data": {"path": self.path, "matched_lines": matched_lines}
+        });
+        self.event(event)
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+/// The last event of a search.
+pub(crate) fn summary<W: Write>(out: &mut W, stats: &Stats, elapsed: Duration) -> io::Result<()> {
+    let event = json!({
+        "type": "summary",
+        "data": {
+            "files_searched": stats.files,
+            "files_with_matches": stats.files_with_matches,
+            "matched_lines": stats.matched_lines,
+            "matches": stats.matches,
+            "elapsed_secs": elapsed.as_secs_f64(),
+        }
+    });
+    serde_json::to_writer(&mut *out, &event)?;
+    writeln!(out)
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn events() {
+        let mut out = Vec::new();
+        let mut printer = JsonPrinter::new(&mut out);
+        printer.start_file("poem.txt").unwrap();
+        printer.line(6, 100, "How \"public\"", None).unwrap();
+        printer
+            .line(7, 122, "How public, like a frog", Some(&[19..23]))
+            .unwrap();
+        let stats = Stats {
+            files: 1,
+            files_with_matches: 1,
+            matched_lines: 1,
+            matches: 1,
+        };
+        printer
+            .fuzzy_line(8, 146, "To tell your nme", &[(1, 13..16)])
+            .unwrap();
+        printer
+            .multi_line(9, 163, "To an admiring bog", &[(2, 15..18)])
+            .unwrap();
+        summary(&mut out, &stats, Duration::from_millis(1500)).unwrap();
+
+        let lines: Vec<serde_json::Value> = String::from_utf8(out)
+            .unwrap()
+            .lines()
+            .map(|line| serde_json::from_str(line).unwrap())
+            .collect();
+        assert_eq!(5, lines.len());
+        assert_eq!(
+            json!({"type": "begin", "data": {"path": "poem.txt"}}),
+            lines[0]
+        );
+        assert_eq!("match", lines[1]["type"]);
+        assert_eq!("poem.txt", lines[1]["data"]["path"]);
+        assert_eq!(7, lines[1]["data"]["line_number"]);
+        assert_eq!(
+            json!([{"match": "frog", "start": 19, "end": 23}]),
+            lines[1]["data"]["submatches"]
+        );
+        assert_eq!(
+            json!([{"match": "nme", "start": 13, "end": 16, "distance": 1}]),
+            lines[2]["data"]["submatches"]
+        );
+        assert_eq!(
+            json!([{"match": "bog", "start": 15, "end": 18, "pattern": 2}]),
+            lines[3]["data"]["submatches"]
+        );
+        assert_eq!("summary", lines[4]["type"]);
+        assert_eq!(1.5, lines[4]["data"]["elapsed_secs"]);
+    }
+}
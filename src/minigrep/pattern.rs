@@ -0,0 +1,589 @@
+use super::fold::{folded, Fold};
+use super::fuzzy;
+use aho_corasick::{AhoCorasick, MatchKind};
+use regex::{Captures, Regex, RegexBuilder};
+use std::ops::Range;
+
+// the names of the groups that tell us which of several regexes matched
+const GROUP_PREFIX: &str = "minigrep_pattern_";
+
+/// What `search` looks for in every line.
+///
+/// Plain queries stay plain substring checks, only queries that really use regex syntax go
+/// through the regex engine.
+#[derive(Debug, Clone)]
+pub enum Pattern {
+    Literal(String),
+    /// Any number of literal patterns, found in a single pass over the folded line. `ids`
+    /// has the index every pattern of the matcher was given at.
+    Literals {
+        matcher: AhoCorasick,
+        fold: Fold,
+        ids: Vec<usize>,
+    },
+    /// The regex ignores the case on its own, `fold` only normalizes the lines. With more
+    /// than one of `ids`, each pattern is in a group named after its index.
+    Regex {
+        regex: Regex,
+        fold: Fold,
+        ids: Vec<usize>,
+    },
+    /// Substrings at most `max_distance` edits away from the (folded) query.
+    Fuzzy {
+        query: Vec<char>,
+        max_distance: usize,
+        fold: Fold,
+    },
+}
+
+impl Pattern {
+    /// Create a pattern that matches `query` literally.
+    pub fn literal(query: &str, case_sensitive: bool) -> Pattern {
+        Pattern::literals(&[query], Fold::case(!case_sensitive))
+    }
+
+    /// Create a pattern that matches if any of `patterns` is in the line, once both are
+    /// folded with `fold`.
+    ///
+    /// The patterns go into an Aho-Corasick automaton, which finds all of them in one pass
+    /// over the line, no matter how many patterns there are.
+    pub fn literals<S: AsRef<str>>(patterns: &[S], fold: Fold) -> Pattern {
+        let patterns: Vec<_> = patterns.iter().map(AsRef::as_ref).enumerate().collect();
+        Pattern::literal_set(&patterns, fold)
+    }
+
+    // `literals` for patterns that come with their index
+    fn literal_set(patterns: &[(usize, &str)], fold: Fold) -> Pattern {
+        if let [(0, query)] = patterns {
+            if fold.is_identity() {
+                return Pattern::Literal(query.to_string());
+            }
+        }
+        let matcher = AhoCorasick::builder()
+            .match_kind(MatchKind::LeftmostLongest)
+            // lets us search ASCII lines without folding them first
+            .ascii_case_insensitive(fold.ignore_case)
+            .build(patterns.iter().map(|(_, p)| fold.apply(p)))
+            .expect("a literal automaton is always small enough");
+        Pattern::Literals {
+            matcher,
+            fold,
+            ids: patterns.iter().map(|(id, _)| *id).collect(),
+        }
+    }
+
+    /// Create a pattern from a regular expression (character classes, anchors, alternation,
+    /// groups...).
+    ///
+    /// A query without any special characters falls back to a literal pattern,
+    /// so it is not slower than before.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::Pattern;
+    ///
+    /// let pattern = Pattern::regex("^Then .*us", true).unwrap();
+    /// assert!(pattern.is_match("Then there's a pair of us — don't tell!"));
+    /// assert!(!pattern.is_match("They'd banish us, you know."));
+    /// ```
+    pub fn regex(query: &str, case_sensitive: bool) -> Result<Pattern, regex::Error> {
+        Pattern::multi(&[query], Fold::case(!case_sensitive), true)
+    }
+
+    /// Create a pattern that matches if any of `patterns` matches, like `grep -f`.
+    ///
+    /// Literal patterns are found with [`Pattern::literals`], regexes are combined into one
+    /// big alternation. The regex engine only knows the simple case folding, so a regex
+    /// with `fold.ignore_case` won't match 'ß' against "SS" the way literals do.
+    ///
+    /// An empty pattern among others is left out, it would match every line, which is hardly
+    /// ever what a blank line in a list of patterns is meant for. The other patterns keep
+    /// their index.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::{Fold, Pattern};
+    ///
+    /// let patterns = ["frog", "bog", "nobody"];
+    /// let pattern = Pattern::multi(&patterns, Fold::default(), false).unwrap();
+    /// assert!(pattern.is_match("To an admiring bog!"));
+    /// assert_eq!(
+    ///     vec![(2, 4..10), (0, 19..23)],
+    ///     pattern.find_patterns("I'm nobody, like a frog")
+    /// );
+    /// ```
+    pub fn multi<S: AsRef<str>>(
+        patterns: &[S],
+        fold: Fold,
+        regex: bool,
+    ) -> Result<Pattern, regex::Error> {
+        let patterns: Vec<_> = patterns
+            .iter()
+            .map(AsRef::as_ref)
+            .enumerate()
+            .filter(|(_, p)| patterns.len() == 1 || !p.is_empty())
+            .collect();
+        let plain = patterns.iter().all(|(_, p)| regex::escape(p) == *p);
+        if !regex || plain {
+            return Ok(Pattern::literal_set(&patterns, fold));
+        }
+        let source = match &patterns[..] {
+            [(_, query)] => query.to_string(),
+            _ => patterns
+                .iter()
+                .map(|(i, p)| format!("(?P<{}{}>{})", GROUP_PREFIX, i, p))
+                .collect::<Vec<_>>()
+                .join("|"),
+        };
+        // the lines get normalized before the regex sees them, so the regex has to be too
+        let normalize = Fold {
+            ignore_case: false,
+            ..fold
+        };
+        let regex = RegexBuilder::new(&normalize.apply(&source))
+            .case_insensitive(fold.ignore_case)
+            .build()?;
+        Ok(Pattern::Regex {
+            regex,
+            fold: normalize,
+            ids: patterns.iter().map(|(id, _)| *id).collect(),
+        })
+    }
+
+    /// Create a pattern that matches the substrings within `max_distance` typos of `query`,
+    /// counted as Levenshtein distance.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::{Fold, Pattern};
+    ///
+    /// let pattern = Pattern::fuzzy("nobody", 1, Fold::default());
+    /// assert!(pattern.is_match("I'm nbody! Who are you?"));
+    /// assert_eq!(
+    ///     vec![(1, 4..9)],
+    ///     pattern.find_distances("I'm nbody! Who are you?")
+    /// );
+    /// assert!(!pattern.is_match("Are you nbdy, too?"));
+    /// ```
+    pub fn fuzzy(query: &str, max_distance: usize, fold: Fold) -> Pattern {
+        Pattern::Fuzzy {
+            query: fold.apply(query).chars().collect(),
+            max_distance,
+            fold,
+        }
+    }
+
+    pub fn is_match(&self, line: &str) -> bool {
+        match self {
+            Pattern::Fuzzy { .. } => !self.find_distances(line).is_empty(),
+            Pattern::Literal(query) => line.contains(query.as_str()),
+            Pattern::Literals { matcher, fold, .. } if searches_directly(fold, line) => {
+                matcher.is_match(line)
+            }
+            Pattern::Literals { matcher, fold, .. } => matcher.is_match(&fold.apply(line)),
+            Pattern::Regex { regex, fold, .. } if searches_directly(fold, line) => {
+                regex.is_match(line)
+            }
+            Pattern::Regex { regex, fold, .. } => regex.is_match(&fold.apply(line)),
+        }
+    }
+
+    /// How many patterns this was made of, not counting the empty ones `multi` left out.
+    pub fn patterns(&self) -> usize {
+        match self {
+            Pattern::Literals { ids, .. } | Pattern::Regex { ids, .. } => ids.len(),
+            Pattern::Literal(_) | Pattern::Fuzzy { .. } => 1,
+        }
+    }
+
+    /// The byte ranges of all (non overlapping) hits in `line`.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::Pattern;
+    ///
+    /// let pattern = Pattern::literal("FROG", false);
+    /// assert_eq!(vec![2..6, 10..14, 18..22], pattern.find_spans("a frog, a Frog, a frog"));
+    /// ```
+    pub fn find_spans(&self, line: &str) -> Vec<Range<usize>> {
+        match self {
+            Pattern::Literal(query) => line
+                .match_indices(query.as_str())
+                .map(|(start, m)| start..start + m.len())
+                .collect(),
+            _ => self
+                .find_patterns(line)
+                .into_iter()
+                .map(|(_, span)| span)
+                .collect(),
+        }
+    }
+
+    /// Replace every hit in `line` with `replacement`, and return the new line together with
+    /// the spans of the replaced parts in it.
+    ///
+    /// `$0` stands for the whole hit and `$$` for a dollar sign. Regexes can also use their
+    /// groups by number (`$1`) or by name (`${word}`), see [`regex::Captures::expand`]. Other
+    /// patterns read the replacement the same way, any group but 0 is empty for them.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::Pattern;
+    ///
+    /// let pattern = Pattern::regex(r"(\w+) (\w+)", true).unwrap();
+    /// let (line, spans) = pattern.replace("public frog", "$2 $1");
+    /// assert_eq!("frog public", line);
+    /// assert_eq!(vec![0..11], spans);
+    /// ```
+    pub fn replace(&self, line: &str, replacement: &str) -> (String, Vec<Range<usize>>) {
+        // the text for every hit, the hits refer to it by index
+        let mut expanded = Vec::new();
+        let hits = match self {
+            Pattern::Regex { regex, fold, .. } => {
+                let mut find = |line: &str| {
+                    regex
+                        .captures_iter(line)
+                        .map(|captures| {
+                            let mut text = String::new();
+                            captures.expand(replacement, &mut text);
+                            expanded.push(text);
+                            (expanded.len() - 1, captures.get_match().range())
+                        })
+                        .collect()
+                };
+                if searches_directly(fold, line) {
+                    find(line)
+                } else {
+                    folded(line, *fold, find)
+                }
+            }
+            _ => self
+                .find_spans(line)
+                .into_iter()
+                .map(|span| {
+                    let mut text = String::new();
+                    expand_hit(replacement, &line[span.clone()], &mut text);
+                    expanded.push(text);
+                    (expanded.len() - 1, span)
+                })
+                .collect(),
+        };
+
+        let mut new = String::with_capacity(line.len());
+        let mut spans = Vec::new();
+        let mut last = 0;
+        for (i, span) in hits {
+            new.push_str(&line[last..span.start]);
+            let start = new.len();
+            new.push_str(&expanded[i]);
+            spans.push(start..new.len());
+            last = span.end;
+        }
+        new.push_str(&line[last..]);
+        (new, spans)
+    }
+
+    /// Like `find_spans`, but also tells which pattern matched, as the index into the
+    /// patterns given to `multi`. A single query always has index 0.
+    pub fn find_patterns(&self, line: &str) -> Vec<(usize, Range<usize>)> {
+        match self {
+            Pattern::Literal(_) => self
+                .find_spans(line)
+                .into_iter()
+                .map(|span| (0, span))
+                .collect(),
+            Pattern::Literals { matcher, fold, ids } => {
+                let find = |line: &str| {
+                    matcher
+                        .find_iter(line)
+                        .map(|m| (ids[m.pattern().as_usize()], m.range()))
+                        .collect()
+                };
+                if searches_directly(fold, line) {
+                    find(line)
+                } else {
+                    folded(line, *fold, find)
+                }
+            }
+            Pattern::Regex { regex, fold, ids } => {
+                // the groups are only there when there is more than one pattern
+                let find = |line: &str| match ids[..] {
+                    [id] => regex.find_iter(line).map(|m| (id, m.range())).collect(),
+                    _ => regex
+                        .captures_iter(line)
+                        .map(|captures| {
+                            (pattern_id(regex, &captures), captures.get_match().range())
+                        })
+                        .collect(),
+                };
+                if searches_directly(fold, line) {
+                    find(line)
+                } else {
+                    folded(line, *fold, find)
+                }
+            }
+            Pattern::Fuzzy { .. } => self
+                .find_distances(line)
+                .into_iter()
+                .map(|(_, span)| (0, span))
+                .collect(),
+        }
+    }
+
+    /// Like `find_spans`, but also tells how many edits every hit is away from the query.
+    /// Only fuzzy patterns have hits that aren't exact.
+    pub fn find_distances(&self, line: &str) -> Vec<(usize, Range<usize>)> {
+        match self {
+            Pattern::Fuzzy {
+                query,
+                max_distance,
+                fold,
+            } => {
+                let find = |line: &str| fuzzy::find(query, *max_distance, line);
+                if fold.is_identity() {
+                    find(line)
+                } else {
+                    folded(line, *fold, find)
+                }
+            }
+            _ => self
+                .find_spans(line)
+                .into_iter()
+                .map(|span| (0, span))
+                .collect(),
+        }
+    }
+}
+
+// Folding doesn't change ASCII text, except for the case, which the literal matcher ignores
+// on its own. So most lines don't need to be copied before we can search them.
+fn searches_directly(fold: &Fold, line: &str) -> bool {
+    fold.is_identity() || line.is_ascii()
+}
+
+// which of the alternated regexes matched, by the name of the group around it
+fn pattern_id(regex: &Regex, captures: &Captures) -> usize {
+    regex
+        .capture_names()
+        .flatten()
+        .filter(|name| captures.name(name).is_some())
+        .find_map(|name| name.strip_prefix(GROUP_PREFIX)?.parse().ok())
+        .unwrap_or(0)
+}
+
+// Read a replacement the way `Captures::expand` does, so a pattern that became a literal
+// behaves like the regex it was: `$$` is a dollar, `$name` and `${name}` are groups. Literals
+// only have group 0, the whole hit, any other group is empty.
+fn expand_hit(replacement: &str, hit: &str, new: &mut String) {
+    let mut rest = replacement;
+    while let Some(dollar) = rest.find('$') {
+        new.push_str(&rest[..dollar]);
+        rest = &rest[dollar + 1..];
+        if let Some(after) = rest.strip_prefix('$') {
+            new.push('$');
+            rest = after;
+            continue;
+        }
+        let (name, after) = match rest.strip_prefix('{') {
+            Some(braced) => match braced.find('}') {
+                Some(end) => (&braced[..end], &braced[end + 1..]),
+                None => ("", rest),
+            },
+            None => {
+                let end = rest
+                    .find(|c: char| c != '_' && !c.is_ascii_alphanumeric())
+                    .unwrap_or(rest.len());
+                rest.split_at(end)
+            }
+        };
+        if name.is_empty() {
+            // not a group after all
+            new.push('$');
+            continue;
+        }
+        if name.parse() == Ok(0) {
+            new.push_str(hit);
+        }
+        rest = after;
+    }
+    new.push_str(rest);
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minigrep::fold::Normalization;
+
+    #[test]
+    fn plain_query_stays_literal() {
+        let pattern = Pattern::regex("frog", true).unwrap();
+        assert!(matches!(pattern, Pattern::Literal(_)));
+        // a dot is special, so this one needs the regex engine
+        let pattern = Pattern::regex("fr.g", true).unwrap();
+        assert!(matches!(pattern, Pattern::Regex { .. }));
+    }
+
+    #[test]
+    fn regex_features() {
+        let pattern = Pattern::regex(r"^(How|To) \w+", true).unwrap();
+        assert!(pattern.is_match("How dreary to be somebody!"));
+        assert!(pattern.is_match("To an admiring bog!"));
+        assert!(!pattern.is_match("Are you nobody, too?"));
+
+        let pattern = Pattern::regex("[aeiou]{2}", false).unwrap();
+        assert!(pattern.is_match("BOG AND FOOD"));
+        assert!(!pattern.is_match("frog"));
+    }
+
+    #[test]
+    fn spans() {
+        let line = "Then there's a pair of us — don't tell!";
+        let pattern = Pattern::regex("th[a-z]*", false).unwrap();
+        assert_eq!(vec![0..4, 5..10], pattern.find_spans(line));
+        let pattern = Pattern::literal("'", true);
+        assert_eq!(vec![10..11, 33..34], pattern.find_spans(line));
+    }
+
+    #[test]
+    fn spans_survive_lowercasing() {
+        // 'İ' takes two bytes but is three once lowercased
+        let line = "İstanbul, istanbul";
+        let pattern = Pattern::literal("STANBUL", false);
+        assert_eq!(vec![2..9, 12..19], pattern.find_spans(line));
+        let pattern = Pattern::literal("i", false);
+        assert_eq!(vec![0..2, 11..12], pattern.find_spans(line));
+    }
+
+    #[test]
+    fn unicode_folding() {
+        let pattern = Pattern::literal("STRASSE", false);
+        assert_eq!(vec![7..14], pattern.find_spans("Große Straße"));
+        assert!(!Pattern::literal("strasse", true).is_match("Straße"));
+
+        let fold = Fold {
+            ignore_case: true,
+            normalization: Normalization::Nfc,
+        };
+        let decomposed = "CAFE\u{301} au lait";
+        let pattern = Pattern::literals(&["café"], fold);
+        assert_eq!(vec![0..6], pattern.find_spans(decomposed));
+        assert!(!Pattern::literal("café", false).is_match(decomposed));
+
+        // regexes see the normalized line, but the spans still point into the original one
+        let pattern = Pattern::multi(&["^(café)$"], fold, true).unwrap();
+        assert!(!pattern.is_match(decomposed));
+        assert_eq!(vec![0..6], pattern.find_spans("Cafe\u{301}"));
+        assert_eq!(vec![0..5], pattern.find_spans("Café"));
+    }
+
+    #[test]
+    fn folded_hits_dont_overlap() {
+        // both halves of a folded 'ß' or 'ﬀ' are the same char of the line
+        let pattern = Pattern::literal("s", false);
+        let line = "Große Straße";
+        assert_eq!(vec![3..5, 7..8, 11..13], pattern.find_spans(line));
+        let (new, spans) = pattern.replace(line, "x");
+        assert_eq!("Groxe xtraxe", new);
+        assert_eq!(vec![3..4, 6..7, 10..11], spans);
+        let pattern = Pattern::literal("F", false);
+        assert_eq!(vec![1..4], pattern.find_spans("eﬀort"));
+        assert_eq!("exort", pattern.replace("eﬀort", "x").0);
+    }
+
+    #[test]
+    fn many_literals() {
+        let identifiers = ["unwrap", "expect", "panic!", "todo!"];
+        let pattern = Pattern::multi(&identifiers, Fold::default(), false).unwrap();
+        assert!(matches!(pattern, Pattern::Literals { .. }));
+        let line = "let x = y.unwrap().expect(\"z\"); todo!()";
+        assert!(pattern.is_match(line));
+        assert_eq!(
+            vec![(0, 10..16), (1, 19..25), (3, 32..37)],
+            pattern.find_patterns(line)
+        );
+        assert!(!pattern.is_match("let x = y?;"));
+
+        let pattern = Pattern::multi(&identifiers, Fold::case(true), false).unwrap();
+        assert_eq!(vec![6..12], pattern.find_spans("don't PANIC!"));
+    }
+
+    #[test]
+    fn empty_patterns_keep_their_place() {
+        let pattern = Pattern::multi(&["frog", "", "bog"], Fold::default(), false).unwrap();
+        assert_eq!(2, pattern.patterns());
+        assert!(!pattern.is_match("a toad"));
+        assert_eq!(
+            vec![(2, 15..18)],
+            pattern.find_patterns("To an admiring bog!")
+        );
+        let pattern = Pattern::multi(&["", "b.g"], Fold::default(), true).unwrap();
+        assert_eq!(
+            vec![(1, 15..18)],
+            pattern.find_patterns("To an admiring bog!")
+        );
+        // on its own it is what was asked for
+        assert!(Pattern::multi(&[""], Fold::default(), false)
+            .unwrap()
+            .is_match("a toad"));
+    }
+
+    #[test]
+    fn many_regexes() {
+        let pattern =
+            Pattern::multi(&["^Then", r"\bbog\b", "frog"], Fold::default(), true).unwrap();
+        assert_eq!(
+            vec![(1, 15..18)],
+            pattern.find_patterns("To an admiring bog!")
+        );
+        assert_eq!(
+            vec![(0, 0..4)],
+            pattern.find_patterns("Then there's a pair of us")
+        );
+        assert!(!pattern.is_match("bogus"));
+    }
+
+    #[test]
+    fn replacements() {
+        let pattern = Pattern::regex(r"(?P<animal>frog|toad)s?", false).unwrap();
+        let (line, spans) = pattern.replace("Frogs and a toad", "<${animal}>");
+        assert_eq!("<Frog> and a <toad>", line);
+        assert_eq!(vec![0..6, 13..19], spans);
+
+        let pattern = Pattern::literal("bog", true);
+        let (line, spans) = pattern.replace("To an admiring bog!", "$$$0$");
+        assert_eq!("To an admiring $bog$!", line);
+        assert_eq!(vec![15..20], spans);
+        assert_eq!(
+            ("no hits".to_string(), vec![]),
+            pattern.replace("no hits", "x")
+        );
+    }
+
+    #[test]
+    fn plain_regexes_replace_like_regexes() {
+        let line = "a frog, a frog";
+        let plain = Pattern::multi(&["frog"], Fold::default(), true).unwrap();
+        let regex = Pattern::regex("(?:frog)", true).unwrap();
+        for replacement in [
+            "$1", "<$0>", "${0}s", "$0s", "$$1", "${1}x", "$", "${0", "$-",
+        ] {
+            assert_eq!(
+                regex.replace(line, replacement).0,
+                plain.replace(line, replacement).0,
+                "{}",
+                replacement
+            );
+        }
+        assert_eq!("a <frog>, a <frog>", plain.replace(line, "<$0>").0);
+    }
+
+    #[test]
+    fn invalid_regex() {
+        assert!(Pattern::regex("(unclosed", true).is_err());
+    }
+}
@@ -0,0 +1,590 @@
+use super::decode::{encoded_len, Encoding};
+use super::json::JsonPrinter;
+use std::collections::VecDeque;
+use std::io::{self, Write};
+use std::ops::Range;
+
+// the same colors grep uses by default
+const MATCH_COLOR: &str = "\x1b[1;31m";
+const PATH_COLOR: &str = "\x1b[35m";
+const NUMBER_COLOR: &str = "\x1b[32m";
+const SEPARATOR_COLOR: &str = "\x1b[36m";
+const RESET: &str = "\x1b[0m";
+
+/// When to highlight matches with ANSI colors.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum ColorChoice {
+    /// Only if stdout is a terminal.
+    #[default]
+    Auto,
+    Always,
+    Never,
+}
+
+impl std::str::FromStr for ColorChoice {
+    type Err = String;
+
+    fn from_str(s: &str) -> Result<ColorChoice, String> {
+        match s {
+            "auto" => Ok(ColorChoice::Auto),
+            "always" => Ok(ColorChoice::Always),
+            "never" => Ok(ColorChoice::Never),
+            _ => Err(format!(
+                "invalid color '{}', expected auto, always or never",
+                s
+            )),
+        }
+    }
+}
+
+/// What gets printed for every file.
+#[derive(Debug, Clone, Copy, PartialEq, Default)]
+pub enum OutputMode {
+    /// The matching lines (and their context).
+    #[default]
+    Lines,
+    /// Only the number of matching lines.
+    Count,
+    /// Only the paths of files with a match.
+    FilesWithMatches,
+    /// Only the paths of files without a match.
+    FilesWithoutMatch,
+}
+
+/// How the `Printer` formats its lines.
+#[derive(Debug, Clone, Copy, Default)]
+pub struct PrintOptions {
+    pub mode: OutputMode,
+    pub line_numbers: bool,
+    /// Prefix lines (or with `only_matching` the matches) with their byte offset in the input,
+    /// counted in the bytes of the file whatever its encoding.
+    pub byte_offset: bool,
+    pub before: usize,
+    pub after: usize,
+    pub color: bool,
+    /// Print only the matched parts of a line, each on its own line.
+    pub only_matching: bool,
+    /// Prefix every line with the path of its file.
+    pub show_paths: bool,
+    /// Print JSON Lines instead of text, see `JsonPrinter`.
+    pub json: bool,
+}
+
+/// Where the lines of a search end up.
+pub trait Sink {
+    /// Start with the lines of the file at `path`.
+    fn start_file(&mut self, path: &str) -> io::Result<()>;
+
+    /// Feed the next line of the input. `line_number` starts at 1, `offset` is the byte
+    /// offset of the line in the input, after a byte order mark.
+    ///
+    /// `spans` is `None` for lines that didn't match, otherwise it holds the byte ranges
+    /// of the hits within `text`.
+    fn line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        spans: Option<&[Range<usize>]>,
+    ) -> io::Result<()>;
+
+    /// Feed a line that matched a fuzzy pattern. Every hit comes with its distance to the
+    /// query, which printers can show or ignore.
+    fn fuzzy_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        hits: &[(usize, Range<usize>)],
+    ) -> io::Result<()> {
+        let spans: Vec<_> = hits.iter().map(|(_, span)| span.clone()).collect();
+        self.line(line_number, offset, text, Some(&spans))
+    }
+
+    /// Feed a line that matched a pattern with more than one part, see `Pattern::multi`.
+    /// Every hit comes with the index of the pattern it is a hit for.
+    fn multi_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        hits: &[(usize, Range<usize>)],
+    ) -> io::Result<()> {
+        let spans: Vec<_> = hits.iter().map(|(_, span)| span.clone()).collect();
+        self.line(line_number, offset, text, Some(&spans))
+    }
+
+    /// The lines of the file are decoded from `encoding`, so a hit at a byte offset in a line
+    /// can be somewhere else in the file.
+    fn decoded_from(&mut self, _encoding: Encoding) {}
+
+    /// The file is binary and has a matching line, which we don't print.
+    fn binary_match(&mut self) -> io::Result<()>;
+
+    /// The file is done, `matched_lines` of its lines matched.
+    fn end_file(&mut self, matched_lines: usize) -> io::Result<()>;
+
+    fn flush(&mut self) -> io::Result<()>;
+}
+
+/// The text `Printer` or the `JsonPrinter`, whichever `options` asks for.
+pub fn sink<'a, W: Write + 'a>(out: W, options: PrintOptions) -> Box<dyn Sink + 'a> {
+    if options.json {
+        Box::new(JsonPrinter::new(out))
+    } else {
+        Box::new(Printer::new(out, options))
+    }
+}
+
+/// Prints the lines `execute` produces, grep style.
+///
+/// Lines are fed one at a time, so the printer only remembers the last `before` lines
+/// instead of the whole file. Overlapping context windows get merged and groups that
+/// are not adjacent are separated by `--`. When searching several files every line is
+/// prefixed with the path it came from.
+pub struct Printer<W: Write> {
+    out: W,
+    options: PrintOptions,
+    file: String,
+    encoding: Encoding,
+    // the lines we might still need as before-context: (line number, byte offset, text)
+    pending: VecDeque<(usize, usize, String)>,
+    after_left: usize,
+    last_printed: Option<usize>,
+    printed_any: bool,
+}
+
+impl<W: Write> Printer<W> {
+    pub fn new(out: W, mut options: PrintOptions) -> Printer<W> {
+        if options.only_matching {
+            // there is nothing to show around a match if we only print the match itself
+            options.before = 0;
+            options.after = 0;
+        }
+        Printer {
+            out,
+            options,
+            file: String::new(),
+            encoding: Encoding::Utf8,
+            pending: VecDeque::with_capacity(options.before),
+            after_left: 0,
+            last_printed: None,
+            printed_any: false,
+        }
+    }
+
+    /// Give back the writer, e.g. the buffer a file was printed into.
+    pub fn into_inner(self) -> W {
+        self.out
+    }
+
+    // groups are only separated when context was requested, just like grep
+    fn separate(&mut self, next: usize) -> io::Result<()> {
+        if self.options.before == 0 && self.options.after == 0 {
+            return Ok(());
+        }
+        let separate = match self.last_printed {
+            Some(last) => next > last + 1,
+            None => self.printed_any,
+        };
+        if separate {
+            write!(self.out, "{}", separator(&self.options))?;
+        }
+        Ok(())
+    }
+
+    // `label` is a field of the prefix, e.g. which patterns matched
+    fn write(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        spans: &[Range<usize>],
+        marker: char,
+        label: &str,
+    ) -> io::Result<()> {
+        self.prefix(line_number, offset, marker, label)?;
+        // we print trimmed lines, so the spans have to move along
+        let trimmed = text.trim();
+        let start = text.len() - text.trim_start().len();
+        let mut printed = 0;
+        if self.options.color {
+            for span in spans {
+                let from = span
+                    .start
+                    .saturating_sub(start)
+                    .clamp(printed, trimmed.len());
+                let to = span.end.saturating_sub(start).clamp(from, trimmed.len());
+                if from == to {
+                    continue;
+                }
+                write!(
+                    self.out,
+                    "{}{}{}{}",
+                    &trimmed[printed..from],
+                    MATCH_COLOR,
+                    &trimmed[from..to],
+                    RESET
+                )?;
+                printed = to;
+            }
+        }
+        writeln!(self.out, "{}", &trimmed[printed..])
+    }
+
+    fn write_matches(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        spans: &[Range<usize>],
+        labels: Option<(char, &[usize])>,
+    ) -> io::Result<()> {
+        for (i, span) in spans.iter().enumerate() {
+            if span.is_empty() {
+                continue;
+            }
+            let label = labels.map_or(String::new(), |(name, values)| label(name, &values[i..=i]));
+            let offset = offset + encoded_len(&text[..span.start], self.encoding);
+            self.prefix(line_number, offset, ':', &label)?;
+            let matched = self.colored(&text[span.clone()], MATCH_COLOR);
+            writeln!(self.out, "{}", matched)?;
+        }
+        Ok(())
+    }
+
+    // A line with hits, and before it the context lines it needs. `labels` has a value for
+    // every hit, they go in front of the hit with only_matching and of the line otherwise.
+    fn matched(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        spans: &[Range<usize>],
+        labels: Option<(char, &[usize])>,
+    ) -> io::Result<()> {
+        let first = self.pending.front().map_or(line_number, |(n, _, _)| *n);
+        self.separate(first)?;
+        while let Some((n, offset, pending)) = self.pending.pop_front() {
+            self.write(n, offset, &pending, &[], '-', "")?;
+        }
+        if self.options.only_matching {
+            self.write_matches(line_number, offset, text, spans, labels)?;
+        } else {
+            let label = labels.map_or(String::new(), |(name, values)| label(name, values));
+            self.write(line_number, offset, text, spans, ':', &label)?;
+        }
+        self.after_left = self.options.after;
+        Ok(())
+    }
+
+    fn prefix(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        marker: char,
+        label: &str,
+    ) -> io::Result<()> {
+        self.last_printed = Some(line_number);
+        self.printed_any = true;
+        let marker = self.colored(&marker.to_string(), SEPARATOR_COLOR);
+        if self.options.show_paths {
+            let path = self.colored(&self.file, PATH_COLOR);
+            write!(self.out, "{}{}", path, marker)?;
+        }
+        if self.options.line_numbers {
+            let number = self.colored(&line_number.to_string(), NUMBER_COLOR);
+            write!(self.out, "{}{}", number, marker)?;
+        }
+        if self.options.byte_offset {
+            let offset = self.colored(&offset.to_string(), NUMBER_COLOR);
+            write!(self.out, "{}{}", offset, marker)?;
+        }
+        if !label.is_empty() {
+            let label = self.colored(label, NUMBER_COLOR);
+            write!(self.out, "{}{}", label, marker)?;
+        }
+        Ok(())
+    }
+
+    fn colored(&self, text: &str, color: &str) -> String {
+        if self.options.color {
+            format!("{}{}{}", color, text, RESET)
+        } else {
+            text.to_string()
+        }
+    }
+}
+
+impl<W: Write> Sink for Printer<W> {
+    /// Start printing the lines of another file, prefixed by `path` if `show_paths` is set.
+    fn start_file(&mut self, path: &str) -> io::Result<()> {
+        self.file = path.to_string();
+        self.encoding = Encoding::Utf8;
+        self.pending.clear();
+        self.after_left = 0;
+        self.last_printed = None;
+        Ok(())
+    }
+
+    fn line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        spans: Option<&[Range<usize>]>,
+    ) -> io::Result<()> {
+        if let Some(spans) = spans {
+            self.matched(line_number, offset, text, spans, None)?;
+        } else if self.after_left > 0 {
+            self.write(line_number, offset, text, &[], '-', "")?;
+            self.after_left -= 1;
+        } else if self.options.before > 0 {
+            if self.pending.len() == self.options.before {
+                self.pending.pop_front();
+            }
+            self.pending
+                .push_back((line_number, offset, text.to_string()));
+        }
+        Ok(())
+    }
+
+    /// Fuzzy hits say how many edits away from the query they are, like `d=0,1:` in front
+    /// of the line.
+    fn fuzzy_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        hits: &[(usize, Range<usize>)],
+    ) -> io::Result<()> {
+        let (distances, spans): (Vec<_>, Vec<_>) = hits.iter().cloned().unzip();
+        self.matched(line_number, offset, text, &spans, Some(('d', &distances)))
+    }
+
+    /// The hits of several patterns say which one they are a hit for, like `p=1,3:` in
+    /// front of the line. The patterns are numbered from 1, so for the patterns of a `-f`
+    /// file that is their line.
+    fn multi_line(
+        &mut self,
+        line_number: usize,
+        offset: usize,
+        text: &str,
+        hits: &[(usize, Range<usize>)],
+    ) -> io::Result<()> {
+        let (numbers, spans): (Vec<_>, Vec<_>) =
+            hits.iter().map(|(id, span)| (id + 1, span.clone())).unzip();
+        self.matched(line_number, offset, text, &spans, Some(('p', &numbers)))
+    }
+
+    fn decoded_from(&mut self, encoding: Encoding) {
+        self.encoding = encoding;
+    }
+
+    fn binary_match(&mut self) -> io::Result<()> {
+        writeln!(self.out, "Binary file {} matches", self.file)
+    }
+
+    fn end_file(&mut self, matched_lines: usize) -> io::Result<()> {
+        let path = self.colored(&self.file, PATH_COLOR);
+        match self.options.mode {
+            OutputMode::Count if self.options.show_paths => {
+                let marker = self.colored(":", SEPARATOR_COLOR);
+                writeln!(self.out, "{}{}{}", path, marker, matched_lines)
+            }
+            OutputMode::Count => writeln!(self.out, "{}", matched_lines),
+            OutputMode::FilesWithMatches if matched_lines > 0 => writeln!(self.out, "{}", path),
+            OutputMode::FilesWithoutMatch if matched_lines == 0 => writeln!(self.out, "{}", path),
+            _ => Ok(()),
+        }
+    }
+
+    fn flush(&mut self) -> io::Result<()> {
+        self.out.flush()
+    }
+}
+
+// The prefix field that says something about the hits of a line, or about a single hit.
+fn label(name: char, values: &[usize]) -> String {
+    let values: Vec<_> = values.iter().map(usize::to_string).collect();
+    format!("{}={}", name, values.join(","))
+}
+
+/// The line between two groups of context lines, empty if there is no context.
+pub(crate) fn separator(options: &PrintOptions) -> String {
+    match (options.before + options.after, options.color) {
+        _ if options.only_matching || options.json => String::new(),
+        _ if options.mode != OutputMode::Lines => String::new(),
+        (0, _) => String::new(),
+        (_, true) => format!("{}--{}\n", SEPARATOR_COLOR, RESET),
+        (_, false) => String::from("--\n"),
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::minigrep::{Fold, Pattern};
+
+    fn print(contents: &str, query: &str, options: PrintOptions) -> String {
+        let mut out = Vec::new();
+        let mut printer = Printer::new(&mut out, options);
+        let mut offset = 0;
+        for (i, line) in contents.lines().enumerate() {
+            let spans: Vec<_> = line
+                .match_indices(query)
+                .map(|(start, m)| start..start + m.len())
+                .collect();
+            let spans = (!spans.is_empty()).then_some(&spans[..]);
+            printer.line(i + 1, offset, line, spans).unwrap();
+            offset += line.len() + 1;
+        }
+        String::from_utf8(out).unwrap()
+    }
+
+    fn context(line_numbers: bool, before: usize, after: usize) -> PrintOptions {
+        PrintOptions {
+            line_numbers,
+            before,
+            after,
+            ..PrintOptions::default()
+        }
+    }
+
+    #[test]
+    fn line_numbers() {
+        let contents = "one\ntwo\nthree\n";
+        assert_eq!("2:two\n", print(contents, "tw", context(true, 0, 0)));
+        assert_eq!("two\n", print(contents, "tw", context(false, 0, 0)));
+    }
+
+    #[test]
+    fn context_groups_get_separated() {
+        let contents = "a\nb\nmatch\nc\nd\ne\nf\nmatch\ng\n";
+        assert_eq!(
+            "2-b\n3:match\n4-c\n--\n7-f\n8:match\n9-g\n",
+            print(contents, "match", context(true, 1, 1))
+        );
+    }
+
+    #[test]
+    fn path_prefix() {
+        let mut out = Vec::new();
+        let options = PrintOptions {
+            show_paths: true,
+            ..context(true, 0, 1)
+        };
+        let mut printer = Printer::new(&mut out, options);
+        printer.start_file("a.txt").unwrap();
+        printer.line(1, 0, "match", Some(&[0..5])).unwrap();
+        printer.line(2, 6, "after", None).unwrap();
+        printer.start_file("b.txt").unwrap();
+        printer.line(1, 0, "match", Some(&[0..5])).unwrap();
+        assert_eq!(
+            "a.txt:1:match\na.txt-2-after\n--\nb.txt:1:match\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn overlapping_context_is_merged() {
+        let contents = "a\nmatch\nb\nmatch\nc\nd\n";
+        assert_eq!(
+            "a\nmatch\nb\nmatch\nc\nd\n",
+            print(contents, "match", context(false, 2, 2))
+        );
+    }
+
+    #[test]
+    fn count_and_file_lists() {
+        let mut out = Vec::new();
+        for mode in [
+            OutputMode::Count,
+            OutputMode::FilesWithMatches,
+            OutputMode::FilesWithoutMatch,
+        ] {
+            let options = PrintOptions {
+                mode,
+                show_paths: true,
+                ..PrintOptions::default()
+            };
+            let mut printer = Printer::new(&mut out, options);
+            printer.start_file("a.txt").unwrap();
+            printer.end_file(2).unwrap();
+            printer.start_file("b.txt").unwrap();
+            printer.end_file(0).unwrap();
+        }
+        assert_eq!(
+            "a.txt:2\nb.txt:0\na.txt\nb.txt\n",
+            String::from_utf8(out).unwrap()
+        );
+    }
+
+    #[test]
+    fn highlight_matches() {
+        let options = PrintOptions {
+            color: true,
+            ..PrintOptions::default()
+        };
+        assert_eq!(
+            "a \x1b[1;31mfrog\x1b[0m, a \x1b[1;31mfrog\x1b[0m!\n",
+            print("  a frog, a frog!", "frog", options)
+        );
+    }
+
+    #[test]
+    fn only_matching_with_byte_offsets() {
+        let options = PrintOptions {
+            only_matching: true,
+            byte_offset: true,
+            ..PrintOptions::default()
+        };
+        assert_eq!(
+            "13:us\n31:us\n",
+            print("nobody!\nThen us.\nThey'd banish us", "us", options)
+        );
+    }
+
+    #[test]
+    fn which_pattern_matched() {
+        let pattern = Pattern::multi(&["toad", "frog", "b.g"], Fold::default(), true);
+        let pattern = pattern.unwrap();
+        let line = "a frog in a bog, a frog";
+        let print = |options: PrintOptions| {
+            let mut out = Vec::new();
+            let mut printer = Printer::new(&mut out, options);
+            let hits = pattern.find_patterns(line);
+            printer.multi_line(1, 0, line, &hits).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!(
+            "p=2,3,2:a frog in a bog, a frog\n",
+            print(PrintOptions::default())
+        );
+        let options = PrintOptions {
+            only_matching: true,
+            line_numbers: true,
+            ..PrintOptions::default()
+        };
+        assert_eq!("1:p=2:frog\n1:p=3:bog\n1:p=2:frog\n", print(options));
+    }
+
+    #[test]
+    fn fuzzy_distances() {
+        let pattern = Pattern::fuzzy("frog", 1, Fold::default());
+        let line = "a frog, a frg";
+        let print = |options: PrintOptions| {
+            let mut out = Vec::new();
+            let mut printer = Printer::new(&mut out, options);
+            let hits = pattern.find_distances(line);
+            printer.fuzzy_line(1, 0, line, &hits).unwrap();
+            String::from_utf8(out).unwrap()
+        };
+        assert_eq!("d=0,1:a frog, a frg\n", print(PrintOptions::default()));
+        let options = PrintOptions {
+            only_matching: true,
+            ..PrintOptions::default()
+        };
+        assert_eq!("d=0:frog\nd=1:frg\n", print(options));
+    }
+}
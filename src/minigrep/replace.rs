@@ -0,0 +1,294 @@
+use super::{MinigrepError, Pattern};
+use std::borrow::Cow;
+use std::fs::{self, File, OpenOptions};
+use std::io::{self, Write};
+use std::ops::Range;
+use std::path::{Path, PathBuf};
+use std::sync::atomic::{AtomicUsize, Ordering};
+
+// lines of context around every change in a diff, like `diff -u`
+const CONTEXT: usize = 3;
+
+/// A file with every hit of a pattern replaced, line by line.
+pub(crate) struct Rewrite<'a> {
+    // both keep their line endings, `new[i]` is what became of `old[i]`
+    old: Vec<&'a str>,
+    new: Vec<Cow<'a, str>>,
+}
+
+impl<'a> Rewrite<'a> {
+    pub(crate) fn new(pattern: &Pattern, contents: &'a str, replacement: &str) -> Rewrite<'a> {
+        let old: Vec<_> = contents.split_inclusive('\n').collect();
+        let new = old
+            .iter()
+            .map(|&line| {
+                let text = line.trim_end_matches(['\n', '\r']);
+                if !pattern.is_match(text) {
+                    return Cow::Borrowed(line);
+                }
+                let (mut replaced, _) = pattern.replace(text, replacement);
+                replaced.push_str(&line[text.len()..]);
+                Cow::Owned(replaced)
+            })
+            .collect();
+        Rewrite { old, new }
+    }
+
+    /// The indexes of the lines that look different after the replacement.
+    fn changed(&self) -> Vec<usize> {
+        (0..self.old.len())
+            .filter(|&i| self.old[i] != self.new[i])
+            .collect()
+    }
+
+    pub(crate) fn is_changed(&self) -> bool {
+        !self.changed().is_empty()
+    }
+
+    pub(crate) fn contents(&self) -> String {
+        self.new.concat()
+    }
+
+    /// The changes as a unified diff, the way `diff -u` or `git diff` print them.
+    pub(crate) fn diff(&self, path: &str) -> String {
+        let changed = self.changed();
+        if changed.is_empty() {
+            return String::new();
+        }
+        // like git we mark the old and new side, but an absolute path can't take a prefix
+        let mut diff = if Path::new(path).is_absolute() {
+            format!("--- {}\n+++ {}\n", path, path)
+        } else {
+            format!("--- a/{}\n+++ b/{}\n", path, path)
+        };
+        for hunk in hunks(&changed, self.old.len()) {
+            // a replacement can contain newlines, so a line can turn into several
+            let new_start: usize = self.new[..hunk.start].iter().map(|l| line_count(l)).sum();
+            let new_len: usize = self.new[hunk.clone()].iter().map(|l| line_count(l)).sum();
+            diff.push_str(&format!(
+                "@@ -{} +{} @@\n",
+                range(hunk.start, hunk.len()),
+                range(new_start, new_len)
+            ));
+            let mut i = hunk.start;
+            while i < hunk.end {
+                if self.old[i] == self.new[i] {
+                    push_line(&mut diff, ' ', self.old[i]);
+                    i += 1;
+                    continue;
+                }
+                // a run of changed lines shows all the old lines first, then all the new ones
+                let run = i..(i..hunk.end)
+                    .find(|&j| self.old[j] == self.new[j])
+                    .unwrap_or(hunk.end);
+                for line in &self.old[run.clone()] {
+                    push_line(&mut diff, '-', line);
+                }
+                for line in self.new[run.clone()]
+                    .iter()
+                    .flat_map(|l| l.split_inclusive('\n'))
+                {
+                    push_line(&mut diff, '+', line);
+                }
+                i = run.end;
+            }
+        }
+        diff
+    }
+}
+
+// Group the changed lines into hunks, changes closer than two contexts share a hunk.
+fn hunks(changed: &[usize], lines: usize) -> Vec<Range<usize>> {
+    let mut hunks: Vec<Range<usize>> = Vec::new();
+    for &i in changed {
+        let start = i.saturating_sub(CONTEXT);
+        let end = (i + CONTEXT + 1).min(lines);
+        match hunks.last_mut() {
+            Some(last) if start <= last.end => last.end = end,
+            _ => hunks.push(start..end),
+        }
+    }
+    hunks
+}
+
+fn line_count(line: &str) -> usize {
+    line.split_inclusive('\n').count()
+}
+
+// the "start,len" of a hunk header, counting lines from 1 (an empty range starts one earlier)
+fn range(start: usize, len: usize) -> String {
+    if len == 0 {
+        format!("{},0", start)
+    } else {
+        format!("{},{}", start + 1, len)
+    }
+}
+
+fn push_line(diff: &mut String, marker: char, line: &str) {
+    diff.push(marker);
+    diff.push_str(line);
+    if !line.ends_with('\n') {
+        diff.push_str("\n\\ No newline at end of file\n");
+    }
+}
+
+/// Replace the contents of `path` without ever leaving a half written file behind.
+///
+/// The new contents go into a temporary file next to the old one, which is then renamed over
+/// it. A rename within a directory is atomic, so readers see either the old or the new file.
+/// A symlink stays a link, the file it points to gets replaced.
+pub(crate) fn write_atomically(path: &Path, contents: &[u8]) -> Result<(), MinigrepError> {
+    let fail = |source| MinigrepError::Io {
+        path: path.to_path_buf(),
+        source,
+    };
+    let target = match fs::canonicalize(path) {
+        Ok(target) => target,
+        Err(e) if e.kind() == io::ErrorKind::NotFound => path.to_path_buf(),
+        Err(e) => return Err(fail(e)),
+    };
+    let (temp, mut file) = create_temp(&target).map_err(fail)?;
+    let mut write = || -> io::Result<()> {
+        // keep the mode of the original, e.g. for scripts
+        match fs::metadata(&target) {
+            Ok(metadata) => file.set_permissions(metadata.permissions())?,
+            Err(e) if e.kind() == io::ErrorKind::NotFound => {}
+            Err(e) => return Err(e),
+        }
+        file.write_all(contents)?;
+        file.sync_all()?;
+        fs::rename(&temp, &target)
+    };
+    write().map_err(|source| {
+        let _ = fs::remove_file(&temp);
+        fail(source)
+    })
+}
+
+// numbers the temporary files, so they don't need to be made up at random
+static NEXT_TEMP: AtomicUsize = AtomicUsize::new(0);
+
+// A new file next to `target`. Whatever is at a name we try already, a file someone else
+// put there or a symlink, is left alone and we try the next name.
+fn create_temp(target: &Path) -> io::Result<(PathBuf, File)> {
+    let dir = match target.parent() {
+        Some(dir) if !dir.as_os_str().is_empty() => dir,
+        _ => Path::new("."),
+    };
+    let name = target.file_name().unwrap_or_default().to_string_lossy();
+    for tries in 1.. {
+        let n = NEXT_TEMP.fetch_add(1, Ordering::Relaxed);
+        let temp = dir.join(format!(".{}.minigrep-{}-{}", name, std::process::id(), n));
+        match OpenOptions::new().write(true).create_new(true).open(&temp) {
+            Ok(file) => return Ok((temp, file)),
+            Err(e) if e.kind() == io::ErrorKind::AlreadyExists && tries < 100 => {}
+            Err(e) => return Err(e),
+        }
+    }
+    unreachable!("we give up after a hundred tries")
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    const POEM: &str = "I'm nobody! Who are you?
+Are you nobody, too?
+Then there's a pair of us - don't tell!
+They'd banish us, you know.
+
+How dreary to be somebody!
+How public, like a frog
+To tell your name the livelong day
+To an admiring bog!";
+
+    #[test]
+    fn rewrite_keeps_line_endings() {
+        let pattern = Pattern::regex(r"(\w+) (\w+)!", true).unwrap();
+        let rewrite = Rewrite::new(&pattern, "public frog!\r\nTo an admiring bog!", "$2 $1!");
+        assert!(rewrite.is_changed());
+        assert_eq!("frog public!\r\nTo an bog admiring!", rewrite.contents());
+
+        let pattern = Pattern::literal("frog", true);
+        assert!(!Rewrite::new(&pattern, "a toad", "newt").is_changed());
+    }
+
+    #[test]
+    fn unified_diff() {
+        let pattern = Pattern::literal("nobody", true);
+        let diff = Rewrite::new(&pattern, POEM, "somebody").diff("poem.txt");
+        let expected = "--- a/poem.txt
++++ b/poem.txt
+@@ -1,5 +1,5 @@
+-I'm nobody! Who are you?
+-Are you nobody, too?
++I'm somebody! Who are you?
++Are you somebody, too?
+ Then there's a pair of us - don't tell!
+ They'd banish us, you know.
+ \n";
+        assert_eq!(expected, diff);
+    }
+
+    #[test]
+    fn diff_hunks_and_new_lines() {
+        let pattern = Pattern::regex("(frog|bog)", true).unwrap();
+        let diff = Rewrite::new(&pattern, POEM, "$1\n").diff("poem.txt");
+        let expected = "--- a/poem.txt
++++ b/poem.txt
+@@ -4,6 +4,8 @@
+ They'd banish us, you know.
+ \n How dreary to be somebody!
+-How public, like a frog
++How public, like a frog
++
+ To tell your name the livelong day
+-To an admiring bog!
+\\ No newline at end of file
++To an admiring bog
++!
+\\ No newline at end of file
+";
+        assert_eq!(expected, diff);
+    }
+
+    #[test]
+    fn atomic_write() {
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, "old").unwrap();
+        write_atomically(&path, b"new").unwrap();
+        assert_eq!("new", fs::read_to_string(&path).unwrap());
+        // the temporary file is gone
+        assert_eq!(1, fs::read_dir(dir.path()).unwrap().count());
+    }
+
+    #[cfg(unix)]
+    #[test]
+    fn atomic_write_through_a_symlink() {
+        use std::os::unix::fs::{symlink, PermissionsExt};
+        let dir = tempfile::tempdir().unwrap();
+        let path = dir.path().join("poem.txt");
+        fs::write(&path, "old").unwrap();
+        fs::set_permissions(&path, fs::Permissions::from_mode(0o751)).unwrap();
+        let link = dir.path().join("link.txt");
+        symlink(&path, &link).unwrap();
+        // someone put links where the next temporary files go
+        let planted = dir.path().join("planted");
+        fs::write(&planted, "keep").unwrap();
+        let next = NEXT_TEMP.load(Ordering::Relaxed);
+        for n in next..next + 5 {
+            let name = format!(".poem.txt.minigrep-{}-{}", std::process::id(), n);
+            symlink(&planted, dir.path().join(name)).unwrap();
+        }
+        write_atomically(&link, b"new").unwrap();
+        assert!(fs::symlink_metadata(&link)
+            .unwrap()
+            .file_type()
+            .is_symlink());
+        assert_eq!("new", fs::read_to_string(&path).unwrap());
+        let mode = fs::metadata(&path).unwrap().permissions().mode();
+        assert_eq!(0o751, mode & 0o777);
+        assert_eq!("keep", fs::read_to_string(&planted).unwrap());
+    }
+}
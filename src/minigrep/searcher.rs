@@ -0,0 +1,154 @@
+use super::Pattern;
+use std::ops::Range;
+use std::str::SplitInclusive;
+
+/// A matching line together with its position in the searched text.
+#[derive(Debug, PartialEq)]
+pub struct Match<'a> {
+    /// Starts at 1, like in every editor.
+    pub line_number: usize,
+    /// Where the line starts in the searched text, in bytes.
+    pub offset: usize,
+    /// The line with the surrounding whitespace trimmed.
+    pub line: &'a str,
+    /// The whole line, only without its line ending.
+    pub untrimmed: &'a str,
+    /// Where the hits are in `line`. Hits reaching into the trimmed whitespace are cut off
+    /// there, hits that lie completely in it are left out.
+    pub spans: Vec<Range<usize>>,
+}
+
+/// Searches text lazily, one line at a time.
+///
+/// Nothing is collected up front, so a caller that only wants the first few matches only pays
+/// for the lines up to them.
+///
+/// # Examples
+///
+/// ```
+/// use rust_book::{Pattern, Searcher};
+///
+/// let searcher = Searcher::new(Pattern::literal("nobody", true));
+/// let poem = "I'm nobody! Who are you?\nAre you nobody, too?\nThen there's a pair of us";
+/// let first = searcher.matches(poem).next().unwrap();
+/// assert_eq!(1, first.line_number);
+/// assert_eq!(vec![4..10], first.spans);
+///
+/// let offsets: Vec<_> = searcher.matches(poem).map(|m| m.offset).collect();
+/// assert_eq!(vec![0, 25], offsets);
+/// ```
+#[derive(Debug, Clone)]
+pub struct Searcher {
+    pattern: Pattern,
+}
+
+impl Searcher {
+    pub fn new(pattern: Pattern) -> Searcher {
+        Searcher { pattern }
+    }
+
+    pub fn pattern(&self) -> &Pattern {
+        &self.pattern
+    }
+
+    /// An iterator over the lines of `contents` that match.
+    pub fn matches<'s, 'a>(&'s self, contents: &'a str) -> Matches<'s, 'a> {
+        Matches::new(&self.pattern, contents)
+    }
+}
+
+/// The iterator returned by [`Searcher::matches`].
+#[derive(Debug)]
+pub struct Matches<'s, 'a> {
+    pattern: &'s Pattern,
+    lines: SplitInclusive<'a, char>,
+    line_number: usize,
+    offset: usize,
+}
+
+impl<'s, 'a> Matches<'s, 'a> {
+    pub(crate) fn new(pattern: &'s Pattern, contents: &'a str) -> Matches<'s, 'a> {
+        Matches {
+            pattern,
+            lines: contents.split_inclusive('\n'),
+            line_number: 0,
+            offset: 0,
+        }
+    }
+}
+
+impl<'s, 'a> Iterator for Matches<'s, 'a> {
+    type Item = Match<'a>;
+
+    fn next(&mut self) -> Option<Match<'a>> {
+        for raw in self.lines.by_ref() {
+            self.line_number += 1;
+            let offset = self.offset;
+            self.offset += raw.len();
+            // the same line endings `str::lines` strips
+            let untrimmed = raw.strip_suffix('\n').unwrap_or(raw);
+            let untrimmed = untrimmed.strip_suffix('\r').unwrap_or(untrimmed);
+            if !self.pattern.is_match(untrimmed) {
+                continue;
+            }
+            let line = untrimmed.trim();
+            // the spans are found in the untrimmed line, so anchors like '^' keep their meaning
+            let start = untrimmed.len() - untrimmed.trim_start().len();
+            let end = start + line.len();
+            let spans = self
+                .pattern
+                .find_spans(untrimmed)
+                .into_iter()
+                .filter(|span| {
+                    if span.is_empty() {
+                        (start..=end).contains(&span.start)
+                    } else {
+                        span.start < end && span.end > start
+                    }
+                })
+                .map(|span| span.start.max(start) - start..span.end.min(end) - start)
+                .collect();
+            return Some(Match {
+                line_number: self.line_number,
+                offset,
+                line,
+                untrimmed,
+                spans,
+            });
+        }
+        None
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    #[test]
+    fn offsets_and_untrimmed_lines() {
+        let contents = "How dreary\r\n  to be somebody!\n\nHow public, like a frog";
+        let searcher = Searcher::new(Pattern::regex("^ *[tT]o", true).unwrap());
+        let found: Vec<_> = searcher.matches(contents).collect();
+        assert_eq!(
+            vec![Match {
+                line_number: 2,
+                offset: 12,
+                line: "to be somebody!",
+                untrimmed: "  to be somebody!",
+                spans: vec![0..2],
+            }],
+            found
+        );
+    }
+
+    #[test]
+    fn stops_early() {
+        let contents = "frog\n".repeat(1000);
+        let searcher = Searcher::new(Pattern::literal("frog", true));
+        let mut matches = searcher.matches(&contents);
+        let third = matches.by_ref().take(3).last().unwrap();
+        assert_eq!((3, 10), (third.line_number, third.offset));
+        // the rest is still there for whoever wants it
+        assert_eq!(997, matches.count());
+    }
+}
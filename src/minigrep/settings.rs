@@ -0,0 +1,225 @@
+use super::MinigrepError;
+use std::env;
+use std::ffi::OsString;
+use std::fmt;
+use std::fs;
+use std::io;
+use std::path::{Path, PathBuf};
+
+/// The environment variables we read start with this, `MINIGREP_LINE_NUMBER=1` is the
+/// same as `--line-number`.
+pub(crate) const ENV_PREFIX: &str = "MINIGREP_";
+
+/// The project config file, looked for in the current directory and its parents.
+pub(crate) const PROJECT_FILE: &str = ".minigreprc";
+
+/// Where a setting came from.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Source {
+    File(PathBuf),
+    Env(String),
+    CommandLine,
+}
+
+impl fmt::Display for Source {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            Source::File(path) => write!(f, "{}", path.display()),
+            Source::Env(name) => write!(f, "{}", name),
+            Source::CommandLine => write!(f, "command line"),
+        }
+    }
+}
+
+/// A value as it was written down. Whether it fits the option is checked by `Config`.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) enum Value {
+    Switch(bool),
+    Text(String),
+    List(Vec<String>),
+}
+
+/// One option set outside of the command line, named like the long option.
+#[derive(Debug, Clone, PartialEq)]
+pub(crate) struct Setting {
+    pub(crate) name: String,
+    pub(crate) value: Value,
+    pub(crate) source: Source,
+}
+
+/// Collect the settings from the user config file, the project config file and the
+/// environment, in this order. Later settings win over earlier ones.
+pub(crate) fn discover() -> Result<Vec<Setting>, MinigrepError> {
+    let mut settings = Vec::new();
+    if let Some(path) = user_file() {
+        settings.extend(file(&path)?);
+    }
+    if let Some(path) = project_file() {
+        settings.extend(file(&path)?);
+    }
+    settings.extend(vars(env::vars_os())?);
+    Ok(settings)
+}
+
+/// `$XDG_CONFIG_HOME/minigrep/config.toml`, which is usually `~/.config/minigrep/config.toml`.
+fn user_file() -> Option<PathBuf> {
+    let config = env::var_os("XDG_CONFIG_HOME")
+        .filter(|dir| !dir.is_empty())
+        .map(PathBuf::from)
+        .or_else(|| env::var_os("HOME").map(|home| Path::new(&home).join(".config")))?;
+    Some(config.join("minigrep").join("config.toml"))
+}
+
+// the closest one, so a project can live inside another one
+fn project_file() -> Option<PathBuf> {
+    let dir = env::current_dir().ok()?;
+    dir.ancestors()
+        .map(|dir| dir.join(PROJECT_FILE))
+        .find(|path| path.is_file())
+}
+
+/// Read the settings of a TOML file. A file that doesn't exist has none.
+pub(crate) fn file(path: &Path) -> Result<Vec<Setting>, MinigrepError> {
+    match fs::read_to_string(path) {
+        Ok(contents) => parse(&contents, Source::File(path.to_path_buf())),
+        Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Vec::new()),
+        Err(e) => Err(MinigrepError::read(path, e)),
+    }
+}
+
+/// Parse `key = value` lines, the keys are the long options. Switches take `true` or
+/// `false`, options with a value a string or a number, and `--include` and `--exclude`
+/// also a list of strings.
+pub(crate) fn parse(contents: &str, source: Source) -> Result<Vec<Setting>, MinigrepError> {
+    let table: toml::Table = contents
+        .parse()
+        .map_err(|e: toml::de::Error| invalid(&source, e.message().trim_end().to_string()))?;
+    let mut settings = Vec::new();
+    for (name, value) in table {
+        let value = match value {
+            toml::Value::Boolean(on) => Value::Switch(on),
+            toml::Value::String(text) => Value::Text(text),
+            toml::Value::Integer(n) => Value::Text(n.to_string()),
+            toml::Value::Array(items) => Value::List(
+                items
+                    .into_iter()
+                    .map(|item| match item {
+                        toml::Value::String(text) => Ok(text),
+                        other => Err(invalid(
+                            &source,
+                            format!(
+                                "'{}' has a {} in its list, expected strings",
+                                name,
+                                other.type_str()
+                            ),
+                        )),
+                    })
+                    .collect::<Result<_, _>>()?,
+            ),
+            other => {
+                return Err(invalid(
+                    &source,
+                    format!("'{}' can't be a {}", name, other.type_str()),
+                ))
+            }
+        };
+        settings.push(Setting {
+            name,
+            value,
+            source: source.clone(),
+        });
+    }
+    Ok(settings)
+}
+
+/// The `MINIGREP_*` variables, sorted by name. Their values are all text, `Config` decides
+/// what counts as on and off.
+pub(crate) fn vars<I>(vars: I) -> Result<Vec<Setting>, MinigrepError>
+where
+    I: IntoIterator<Item = (OsString, OsString)>,
+{
+    let mut settings = Vec::new();
+    for (key, value) in vars {
+        let Some(name) = key.to_str().and_then(|key| key.strip_prefix(ENV_PREFIX)) else {
+            continue;
+        };
+        let source = Source::Env(format!("{}{}", ENV_PREFIX, name));
+        let value = value
+            .into_string()
+            .map_err(|_| invalid(&source, String::from("the value is not valid UTF-8")))?;
+        settings.push(Setting {
+            name: name.to_lowercase().replace('_', "-"),
+            value: Value::Text(value),
+            source,
+        });
+    }
+    settings.sort_by(|a, b| a.name.cmp(&b.name));
+    Ok(settings)
+}
+
+pub(crate) fn invalid(source: &Source, message: String) -> MinigrepError {
+    MinigrepError::Settings {
+        origin: source.to_string(),
+        message,
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    fn toml(contents: &str) -> Result<Vec<Setting>, MinigrepError> {
+        parse(contents, Source::File(PathBuf::from("config.toml")))
+    }
+
+    #[test]
+    fn toml_values() {
+        let settings =
+            toml("line-number = true\ncontext = 2\ncolor = \"always\"\nexclude = [\"target\"]\n")
+                .unwrap();
+        let values: Vec<_> = settings.iter().map(|s| (&s.name[..], &s.value)).collect();
+        assert_eq!(
+            vec![
+                ("color", &Value::Text(String::from("always"))),
+                ("context", &Value::Text(String::from("2"))),
+                ("exclude", &Value::List(vec![String::from("target")])),
+                ("line-number", &Value::Switch(true)),
+            ],
+            values
+        );
+        assert_eq!(
+            "config.toml: 'context' can't be a float",
+            toml("context = 2.5").unwrap_err().to_string()
+        );
+        assert!(toml("context = ")
+            .unwrap_err()
+            .to_string()
+            .starts_with("config.toml: "));
+    }
+
+    #[test]
+    fn environment_variables() {
+        let vars = [
+            ("PATH", "/bin"),
+            ("MINIGREP_LINE_NUMBER", "1"),
+            ("MINIGREP_COLOR", "never"),
+        ];
+        let settings =
+            super::vars(vars.map(|(k, v)| (OsString::from(k), OsString::from(v)))).unwrap();
+        assert_eq!(
+            vec![
+                Setting {
+                    name: String::from("color"),
+                    value: Value::Text(String::from("never")),
+                    source: Source::Env(String::from("MINIGREP_COLOR")),
+                },
+                Setting {
+                    name: String::from("line-number"),
+                    value: Value::Text(String::from("1")),
+                    source: Source::Env(String::from("MINIGREP_LINE_NUMBER")),
+                },
+            ],
+            settings
+        );
+    }
+}
@@ -0,0 +1,181 @@
+use ignore::overrides::OverrideBuilder;
+use ignore::{DirEntry, Walk, WalkBuilder};
+use std::path::{Path, PathBuf};
+
+/// Which files a directory search looks at.
+#[derive(Debug, Clone, Default)]
+pub struct WalkOptions {
+    /// Only search files matching one of these globs (all files when empty).
+    pub include: Vec<String>,
+    /// Skip files and directories matching one of these globs.
+    pub exclude: Vec<String>,
+    /// Also search hidden files and directories.
+    pub hidden: bool,
+    /// Don't respect `.gitignore` and `.ignore` files.
+    pub no_ignore: bool,
+}
+
+/// The files found below a root, and the entries we couldn't look at on the way.
+#[derive(Debug, Default)]
+pub struct Found {
+    pub files: Vec<PathBuf>,
+    pub errors: Vec<ignore::Error>,
+}
+
+/// Collect the files to search below `root`, sorted by path.
+///
+/// If `root` is a plain file it is returned as is, the filters only apply to what we find
+/// while walking a directory. An entry that can't be read, like a directory without
+/// permission, is skipped and its error kept in `Found::errors`. Only bad globs fail.
+pub fn files(root: &Path, options: &WalkOptions) -> Result<Found, ignore::Error> {
+    if !root.is_dir() {
+        return Ok(Found {
+            files: vec![root.to_path_buf()],
+            errors: Vec::new(),
+        });
+    }
+    Ok(collect(walker(root, options)?))
+}
+
+fn walker(root: &Path, options: &WalkOptions) -> Result<Walk, ignore::Error> {
+    let mut overrides = OverrideBuilder::new(root);
+    for glob in &options.include {
+        overrides.add(glob)?;
+    }
+    for glob in &options.exclude {
+        overrides.add(&format!("!{}", glob))?;
+    }
+
+    let hidden = options.hidden;
+    let walker = WalkBuilder::new(root)
+        .hidden(!hidden)
+        // an --include glob would otherwise let hidden files through
+        .filter_entry(move |entry| {
+            hidden || entry.depth() == 0 || !entry.file_name().to_string_lossy().starts_with('.')
+        })
+        .git_ignore(!options.no_ignore)
+        .git_global(!options.no_ignore)
+        .git_exclude(!options.no_ignore)
+        .ignore(!options.no_ignore)
+        .parents(!options.no_ignore)
+        // .gitignore files count even if the tree is not a git repository
+        .require_git(false)
+        .overrides(overrides.build()?)
+        .sort_by_file_name(|a, b| a.cmp(b))
+        .build();
+    Ok(walker)
+}
+
+fn collect<I>(entries: I) -> Found
+where
+    I: IntoIterator<Item = Result<DirEntry, ignore::Error>>,
+{
+    let mut found = Found::default();
+    for entry in entries {
+        match entry {
+            Ok(entry) if entry.file_type().is_some_and(|t| t.is_file()) => {
+                found.files.push(entry.into_path())
+            }
+            Ok(_) => {}
+            // the rest of the tree is still worth searching
+            Err(e) => found.errors.push(e),
+        }
+    }
+    found
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use std::fs;
+
+    fn tree() -> tempfile::TempDir {
+        let temp = tempfile::tempdir().unwrap();
+        let root = temp.path();
+        fs::create_dir_all(root.join("src/nested")).unwrap();
+        fs::create_dir_all(root.join("target")).unwrap();
+        fs::write(root.join(".gitignore"), "target/\n").unwrap();
+        fs::write(root.join(".hidden.rs"), "").unwrap();
+        fs::write(root.join("notes.txt"), "").unwrap();
+        fs::write(root.join("src/lib.rs"), "").unwrap();
+        fs::write(root.join("src/nested/mod.rs"), "").unwrap();
+        fs::write(root.join("target/build.rs"), "").unwrap();
+        temp
+    }
+
+    fn relative(root: &Path, files: Vec<PathBuf>) -> Vec<String> {
+        files
+            .iter()
+            .map(|f| {
+                f.strip_prefix(root)
+                    .unwrap()
+                    .to_string_lossy()
+                    .replace('\\', "/")
+            })
+            .collect()
+    }
+
+    #[test]
+    fn respects_hidden_and_gitignore() {
+        let temp = tree();
+        let root = temp.path();
+        let found = files(root, &WalkOptions::default()).unwrap();
+        assert!(found.errors.is_empty());
+        assert_eq!(
+            vec!["notes.txt", "src/lib.rs", "src/nested/mod.rs"],
+            relative(root, found.files)
+        );
+
+        let options = WalkOptions {
+            hidden: true,
+            no_ignore: true,
+            ..WalkOptions::default()
+        };
+        let found = files(root, &options).unwrap();
+        assert_eq!(
+            vec![
+                ".gitignore",
+                ".hidden.rs",
+                "notes.txt",
+                "src/lib.rs",
+                "src/nested/mod.rs",
+                "target/build.rs"
+            ],
+            relative(root, found.files)
+        );
+    }
+
+    #[test]
+    fn include_and_exclude() {
+        let temp = tree();
+        let root = temp.path();
+        let options = WalkOptions {
+            include: vec!["*.rs".to_string()],
+            exclude: vec!["nested".to_string()],
+            ..WalkOptions::default()
+        };
+        let found = files(root, &options).unwrap();
+        assert_eq!(vec!["src/lib.rs"], relative(root, found.files));
+    }
+
+    #[test]
+    fn unreadable_entries_are_skipped() {
+        let temp = tree();
+        let root = temp.path();
+        // we run as root often enough that permissions can't be relied on to fail a read
+        let broken = ignore::Error::WithPath {
+            path: root.join("src/locked"),
+            err: Box::new(ignore::Error::Io(
+                std::io::ErrorKind::PermissionDenied.into(),
+            )),
+        };
+        let walker = walker(root, &WalkOptions::default()).unwrap();
+        let found = collect(std::iter::once(Err(broken)).chain(walker));
+        assert_eq!(
+            vec!["notes.txt", "src/lib.rs", "src/nested/mod.rs"],
+            relative(root, found.files)
+        );
+        assert_eq!(1, found.errors.len());
+        assert!(found.errors[0].to_string().contains("src/locked"));
+    }
+}
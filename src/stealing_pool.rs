@@ -0,0 +1,401 @@
+use crate::thread_pool::{lock, run_job, Job, JobHandle};
+use crossbeam_deque::{Injector, Steal, Stealer, Worker};
+use std::cell::RefCell;
+use std::iter;
+use std::sync::atomic::{self, AtomicBool, AtomicUsize, Ordering};
+use std::sync::{Arc, Condvar, Mutex, PoisonError};
+use std::thread::{self, JoinHandle};
+
+/// A thread pool where every worker has a queue of its own, as an alternative to
+/// `ThreadPool`, whose workers all take their jobs from one queue behind one lock.
+///
+/// Jobs queued from outside the pool go to a shared queue. Jobs queued by a job that runs
+/// on the pool go to the queue of its worker, which runs the newest of them first. A worker
+/// that runs out of jobs takes the oldest job of another worker. So as long as jobs keep
+/// spawning jobs, the workers mostly stay out of each other's way.
+///
+/// The queues are the lock-free deques of `crossbeam-deque`, so taking a job from one only
+/// contends with the workers that go for the same one.
+///
+/// Jobs get to the pool through an `Arc`. Dropping the pool waits for the queued jobs,
+/// unless it happens inside a job. Like in `ThreadPool`, a job that panics is counted in
+/// `failed_jobs`, and a worker thread that dies anyway is replaced by a new one, which takes
+/// over its queue.
+///
+/// # Examples
+///
+/// ```
+/// use rust_book::StealingPool;
+/// use std::sync::{mpsc, Arc};
+///
+/// let pool = Arc::new(StealingPool::new(4));
+/// let (sender, receiver) = mpsc::channel();
+/// for i in 0..10 {
+///     let (inner, sender) = (Arc::clone(&pool), sender.clone());
+///     pool.execute(move || {
+///         for j in 0..10 {
+///             let sender = sender.clone();
+///             inner.execute(move || sender.send(i * 10 + j).unwrap());
+///         }
+///     });
+/// }
+/// drop(sender);
+/// assert_eq!(4950, receiver.iter().sum::<i32>());
+/// ```
+pub struct StealingPool {
+    shared: Arc<Shared>,
+}
+
+struct Shared {
+    // for the jobs from outside the pool
+    injector: Injector<Job>,
+    // the other end of every worker's queue, for the thieves
+    stealers: Vec<Stealer<Job>>,
+    // only when there are sleepers does a new job have to take the `sleep` lock
+    sleepers: AtomicUsize,
+    sleep: Mutex<()>,
+    wake: Condvar,
+    closed: AtomicBool,
+    failed: AtomicUsize,
+    // to join on drop, including the threads that took over from dead ones
+    threads: Mutex<Vec<JoinHandle<()>>>,
+}
+
+// What a worker thread keeps to itself. The owner works from the back of its queue, the
+// thieves take from the front.
+struct Local {
+    // only compared, never followed
+    shared: *const Shared,
+    index: usize,
+    queue: Worker<Job>,
+}
+
+thread_local! {
+    // the worker running on this thread, if any
+    static LOCAL: RefCell<Option<Local>> = const { RefCell::new(None) };
+}
+
+impl StealingPool {
+    /// Create a pool with `number_of_threads` workers.
+    ///
+    /// # Panics
+    ///
+    /// If number_of_threads is 0, or a thread can't be started.
+    pub fn new(number_of_threads: usize) -> StealingPool {
+        assert!(number_of_threads > 0);
+        let queues: Vec<_> = (0..number_of_threads).map(|_| Worker::new_lifo()).collect();
+        let shared = Arc::new(Shared {
+            injector: Injector::new(),
+            stealers: queues.iter().map(Worker::stealer).collect(),
+            sleepers: AtomicUsize::new(0),
+            sleep: Mutex::new(()),
+            wake: Condvar::new(),
+            closed: AtomicBool::new(false),
+            failed: AtomicUsize::new(0),
+            threads: Mutex::new(Vec::with_capacity(number_of_threads)),
+        });
+        for (index, queue) in queues.into_iter().enumerate() {
+            shared
+                .start(index, queue)
+                .expect("failed to start a worker thread");
+        }
+        StealingPool { shared }
+    }
+
+    /// Queue `f`, on the current worker's own queue if called from a job on this pool.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let job: Job = Box::new(f);
+        let outside = LOCAL.with(|local| match &*local.borrow() {
+            Some(local) if std::ptr::eq(local.shared, &*self.shared) => {
+                local.queue.push(job);
+                None
+            }
+            _ => Some(job),
+        });
+        if let Some(job) = outside {
+            self.shared.injector.push(job);
+        }
+        self.shared.wake();
+    }
+
+    /// Queue `f` like `execute`, and get a handle to wait for what it returns.
+    pub fn spawn<F, R>(&self, f: F) -> JobHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (job, handle) = JobHandle::wrap(f);
+        self.execute(job);
+        handle
+    }
+
+    /// How many jobs have panicked so far.
+    pub fn failed_jobs(&self) -> usize {
+        self.shared.failed.load(Ordering::SeqCst)
+    }
+}
+
+impl Drop for StealingPool {
+    fn drop(&mut self) {
+        self.shared.closed.store(true, Ordering::SeqCst);
+        {
+            let _sleep = lock(&self.shared.sleep);
+            self.shared.wake.notify_all();
+        }
+        // the last Arc went away inside a job, a worker can't wait for itself
+        if self.shared.current().is_some() {
+            return;
+        }
+        // a worker that dies now adds the thread taking over before it is gone
+        loop {
+            let Some(thread) = lock(&self.shared.threads).pop() else {
+                break;
+            };
+            let _ = thread.join();
+        }
+    }
+}
+
+// Lives on the stack of a worker thread to notice when the thread dies, which only happens
+// when dropping the panic of a job panics again.
+struct Sentinel {
+    shared: Arc<Shared>,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if !thread::panicking() {
+            return;
+        }
+        if let Ok(Some(local)) = LOCAL.try_with(|local| local.borrow_mut().take()) {
+            self.shared.replace(local.index, local.queue);
+        }
+    }
+}
+
+impl Shared {
+    fn start(self: &Arc<Self>, index: usize, queue: Worker<Job>) -> std::io::Result<()> {
+        let shared = Arc::clone(self);
+        let thread = thread::Builder::new().spawn(move || shared.work(index, queue))?;
+        lock(&self.threads).push(thread);
+        Ok(())
+    }
+
+    fn work(self: &Arc<Self>, index: usize, queue: Worker<Job>) {
+        LOCAL.with(|local| {
+            *local.borrow_mut() = Some(Local {
+                shared: Arc::as_ptr(self),
+                index,
+                queue,
+            })
+        });
+        let _sentinel = Sentinel {
+            shared: Arc::clone(self),
+        };
+        while let Some(job) = self.next_job(index) {
+            run_job(job, |ok| {
+                if !ok {
+                    self.failed.fetch_add(1, Ordering::SeqCst);
+                }
+            });
+        }
+    }
+
+    // A worker thread died, start another one on its queue while there is work left.
+    fn replace(self: &Arc<Self>, index: usize, queue: Worker<Job>) {
+        if self.closed.load(Ordering::SeqCst) && queue.is_empty() {
+            return;
+        }
+        // without a thread the jobs on this queue are lost, the others still get stolen
+        let _ = self.start(index, queue);
+    }
+
+    // The worker of this pool we are running on, if any.
+    fn current(&self) -> Option<usize> {
+        LOCAL.with(|local| match &*local.borrow() {
+            Some(local) if std::ptr::eq(local.shared, self) => Some(local.index),
+            _ => None,
+        })
+    }
+
+    // Wake a worker for a job that was just queued.
+    fn wake(&self) {
+        // pairs with the fence in `next_job`: either the sleeper sees the job, or we see it
+        // sleeping
+        atomic::fence(Ordering::SeqCst);
+        if self.sleepers.load(Ordering::SeqCst) > 0 {
+            // taking the lock makes sure the sleeper is waiting already
+            let _sleep = lock(&self.sleep);
+            self.wake.notify_one();
+        }
+    }
+
+    // Our own newest job, the oldest from outside, or the oldest of another worker. None once
+    // the pool is dropped and there are no jobs left.
+    fn next_job(&self, index: usize) -> Option<Job> {
+        loop {
+            if let Some(job) = self.find(index) {
+                return Some(job);
+            }
+            let sleep = lock(&self.sleep);
+            self.sleepers.fetch_add(1, Ordering::SeqCst);
+            atomic::fence(Ordering::SeqCst);
+            // a job queued after we looked might not have seen us here yet
+            if self.has_jobs() {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                continue;
+            }
+            if self.closed.load(Ordering::SeqCst) {
+                self.sleepers.fetch_sub(1, Ordering::SeqCst);
+                return None;
+            }
+            let sleep = self
+                .wake
+                .wait(sleep)
+                .unwrap_or_else(PoisonError::into_inner);
+            self.sleepers.fetch_sub(1, Ordering::SeqCst);
+            drop(sleep);
+        }
+    }
+
+    fn find(&self, index: usize) -> Option<Job> {
+        LOCAL.with(|local| {
+            let local = local.borrow();
+            let queue = &local.as_ref()?.queue;
+            queue.pop().or_else(|| {
+                // start with the next worker, so the thieves spread out
+                let others = self.stealers.len();
+                iter::repeat_with(|| {
+                    self.injector.steal_batch_and_pop(queue).or_else(|| {
+                        (1..others)
+                            .map(|offset| &self.stealers[(index + offset) % others])
+                            .map(|stealer| stealer.steal_batch_and_pop(queue))
+                            .collect()
+                    })
+                })
+                .find(|steal| !steal.is_retry())
+                .and_then(Steal::success)
+            })
+        })
+    }
+
+    fn has_jobs(&self) -> bool {
+        !self.injector.is_empty() || self.stealers.iter().any(|stealer| !stealer.is_empty())
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+    use crate::thread_pool::Bomb;
+    use std::panic;
+    use std::sync::mpsc;
+
+    #[test]
+    fn nested_jobs_stay_on_their_worker() {
+        let pool = Arc::new(StealingPool::new(2));
+        // keep one worker busy, so nobody steals from the other one
+        let (started, running) = mpsc::channel();
+        let (release, blocked) = mpsc::channel::<()>();
+        pool.execute(move || {
+            started.send(()).unwrap();
+            let _ = blocked.recv();
+        });
+        running.recv().unwrap();
+
+        let (sender, receiver) = mpsc::channel();
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            let index = inner.shared.current().unwrap();
+            for _ in 0..3 {
+                let sender = sender.clone();
+                let shared = Arc::clone(&inner.shared);
+                inner.execute(move || sender.send(shared.current()).unwrap());
+            }
+            sender
+                .send(Some(inner.shared.stealers[index].len()))
+                .unwrap();
+            sender.send(Some(index)).unwrap();
+        });
+        let sent: Vec<_> = receiver.iter().take(5).map(Option::unwrap).collect();
+        // the nested jobs ran on the worker that queued them
+        let index = sent[1];
+        assert_eq!(vec![3, index, index, index, index], sent);
+        assert_eq!(None, pool.shared.current());
+        drop(release);
+    }
+
+    #[test]
+    fn idle_workers_steal() {
+        let pool = Arc::new(StealingPool::new(4));
+        let (sender, receiver) = mpsc::channel();
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            // all queued on this worker, the others have to steal them
+            for _ in 0..8 {
+                let sender = sender.clone();
+                let shared = Arc::clone(&inner.shared);
+                inner.execute(move || {
+                    thread::sleep(std::time::Duration::from_millis(20));
+                    sender.send(shared.current().unwrap()).unwrap();
+                });
+            }
+        });
+        let mut workers: Vec<_> = receiver.iter().take(8).collect();
+        workers.sort();
+        workers.dedup();
+        assert!(workers.len() > 1, "only {:?} did the work", workers);
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        let pool = Arc::new(StealingPool::new(1));
+        let (sender, receiver) = mpsc::channel();
+        let inner = Arc::clone(&pool);
+        pool.execute(move || {
+            // queued on the worker that is about to die
+            for i in 0..3 {
+                let sender = sender.clone();
+                inner.execute(move || sender.send(i).unwrap());
+            }
+            panic::panic_any(Bomb);
+        });
+        // the new thread took over the queue, newest job first
+        let sent: Vec<_> = receiver.iter().take(3).collect();
+        assert_eq!(vec![2, 1, 0], sent);
+        assert_eq!(5, pool.spawn(|| 5).join().unwrap());
+        assert_eq!(1, pool.failed_jobs());
+        assert_eq!(2, lock(&pool.shared.threads).len());
+    }
+
+    #[test]
+    fn panics_and_drop() {
+        // the jobs all come from outside, so the one worker takes them oldest first
+        let pool = StealingPool::new(1);
+        let count = Arc::new(AtomicUsize::new(0));
+        for i in 0..100 {
+            let count = Arc::clone(&count);
+            pool.execute(move || {
+                if i % 10 == 0 {
+                    panic!("job {}", i);
+                }
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        assert!(pool.spawn(|| panic!("oops")).join().is_err());
+        assert_eq!(7, pool.spawn(|| 7).join().unwrap());
+        assert_eq!(11, pool.failed_jobs());
+        for _ in 0..10 {
+            let count = Arc::clone(&count);
+            pool.execute(move || {
+                count.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        // dropping waits for the queued jobs
+        drop(pool);
+        assert_eq!(100, count.load(Ordering::SeqCst));
+    }
+}
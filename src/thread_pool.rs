@@ -0,0 +1,1248 @@
+use std::any::Any;
+use std::collections::VecDeque;
+use std::error::Error;
+use std::fmt;
+use std::io;
+use std::panic::{self, AssertUnwindSafe};
+use std::sync::atomic::{AtomicBool, AtomicUsize, Ordering};
+use std::sync::mpsc::{self, RecvTimeoutError, TryRecvError};
+use std::sync::{Arc, Condvar, Mutex, MutexGuard, PoisonError};
+use std::thread::{self, JoinHandle};
+use std::time::{Duration, Instant};
+
+/// Runs jobs on worker threads.
+///
+/// The number of workers is fixed unless `PoolOptions::max_threads` lets the pool grow while
+/// jobs are piling up, or `resize` changes it.
+///
+/// A job that panics doesn't take its worker down: the panic is caught, counted in
+/// `failed_jobs`, and the worker goes on with the next job. Should a worker thread die
+/// anyway, a new one with a new id takes its place.
+pub struct ThreadPool {
+    shared: Arc<Shared>,
+}
+
+pub(crate) trait FnBox {
+    fn call_box(self: Box<Self>);
+}
+
+impl<F: FnOnce()> FnBox for F {
+    fn call_box(self: Box<Self>) {
+        (*self)();
+    }
+}
+
+pub(crate) type Job = Box<dyn FnBox + Send + 'static>;
+
+/// How a `ThreadPool` is set up, see `ThreadPool::with_options`.
+#[derive(Debug, Clone, Copy)]
+pub struct PoolOptions {
+    /// How many workers run the jobs, and the fewest the pool shrinks to.
+    pub threads: usize,
+    /// The most workers the pool grows to while jobs are waiting. Just `threads` when None.
+    pub max_threads: Option<usize>,
+    /// Start another worker when a job has waited this long and all workers are busy.
+    pub grow_after: Duration,
+    /// Workers beyond `threads` quit when they had nothing to do for this long.
+    pub keep_alive: Duration,
+    /// How many jobs can wait in the queue, no limit when None.
+    pub capacity: Option<usize>,
+    /// What `execute` does when the queue is full.
+    pub overflow: Overflow,
+    /// Print what the workers are doing.
+    pub verbose: bool,
+}
+
+impl Default for PoolOptions {
+    /// A worker per CPU that never grows, an unbounded queue and no printing.
+    fn default() -> PoolOptions {
+        PoolOptions {
+            threads: thread::available_parallelism().map_or(1, |n| n.get()),
+            max_threads: None,
+            grow_after: Duration::from_millis(100),
+            keep_alive: Duration::from_secs(60),
+            capacity: None,
+            overflow: Overflow::default(),
+            verbose: false,
+        }
+    }
+}
+
+/// What happens to a job that comes in while the queue is full.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub enum Overflow {
+    /// Wait until a worker takes a job off the queue.
+    #[default]
+    Block,
+    /// Drop the new job. `try_execute` hands it back instead.
+    Reject,
+    /// Drop the job that has been waiting the longest to make room.
+    DropOldest,
+}
+
+/// What `ThreadPool::shutdown` does with the jobs that are queued but not started yet.
+#[derive(Debug, Clone, Copy, PartialEq, Eq)]
+pub enum ShutdownMode {
+    /// Run them first, as long as the timeout allows.
+    Drain,
+    /// Drop them, only the jobs that are already running get finished.
+    Discard,
+}
+
+/// What happened to the jobs of a pool that was shut down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct ShutdownReport {
+    /// The workers the pool had when it was shut down.
+    pub workers: Vec<WorkerReport>,
+    /// What the workers that retired or died before the shutdown did, all together.
+    pub retired: JobCounts,
+    /// Jobs that never got to a worker: all queued jobs when discarding, and when draining
+    /// the ones still queued once the timeout was up.
+    pub dropped: usize,
+}
+
+/// What a single worker did until the pool was shut down.
+#[derive(Debug, Clone, PartialEq, Eq)]
+pub struct WorkerReport {
+    pub id: usize,
+    /// How many jobs it finished.
+    pub completed: usize,
+    /// How many of its jobs panicked.
+    pub failed: usize,
+    /// Whether it was still busy with a job when the timeout was up. Threads can't be
+    /// killed, so it keeps running in the background and quits after that job.
+    pub running: bool,
+}
+
+/// How many jobs finished and how many panicked.
+#[derive(Debug, Clone, Copy, PartialEq, Eq, Default)]
+pub struct JobCounts {
+    pub completed: usize,
+    pub failed: usize,
+}
+
+impl ShutdownReport {
+    pub fn completed(&self) -> usize {
+        self.retired.completed
+            + self
+                .workers
+                .iter()
+                .map(|worker| worker.completed)
+                .sum::<usize>()
+    }
+
+    pub fn failed(&self) -> usize {
+        self.retired.failed
+            + self
+                .workers
+                .iter()
+                .map(|worker| worker.failed)
+                .sum::<usize>()
+    }
+
+    pub fn still_running(&self) -> usize {
+        self.workers.iter().filter(|worker| worker.running).count()
+    }
+}
+
+// What the pool and its workers share.
+struct Shared {
+    queue: Mutex<Queue>,
+    // a job was queued, or the pool is shutting down
+    available: Condvar,
+    // a job was taken off a full queue, or the pool is shutting down
+    space: Condvar,
+    capacity: Option<usize>,
+    overflow: Overflow,
+    grow_after: Duration,
+    keep_alive: Duration,
+    workers: Mutex<Workers>,
+    // a worker quit, `shutdown` waits for this with the queue lock
+    exit: Condvar,
+    next_id: AtomicUsize,
+    verbose: bool,
+}
+
+// The jobs, and how many workers there are to run them. Both sit behind the same lock, so
+// deciding whether to start or retire a worker can't race with the jobs coming and going.
+struct Queue {
+    // with the time they were queued at
+    jobs: VecDeque<(Instant, Job)>,
+    // no new jobs are accepted, the workers quit once the queue is empty
+    closed: bool,
+    // workers that haven't quit yet, including those about to retire
+    live: usize,
+    // workers waiting for a job
+    idle: usize,
+    // workers that should quit after their current job, because the pool was resized
+    retiring: usize,
+    // a watcher is waiting for the oldest job to have waited long enough to grow the pool
+    watching: bool,
+    min: usize,
+    max: usize,
+}
+
+// The workers that are still around, or quit because the pool was shut down. The ones that
+// retired or died before are only counted, so a pool that keeps growing and shrinking doesn't
+// pile them up.
+#[derive(Default)]
+struct Workers {
+    list: Vec<Worker>,
+    retired: JobCounts,
+}
+
+impl Queue {
+    // the workers that are going to stay
+    fn staying(&self) -> usize {
+        self.live - self.retiring
+    }
+}
+
+impl ThreadPool {
+    /// Create a new ThreadPool
+    ///
+    /// # Penics
+    ///
+    /// The `new` function will panic!("c if n_thread is 0");
+    pub fn new(number_of_threads: usize) -> ThreadPool {
+        ThreadPool::with_options(PoolOptions {
+            threads: number_of_threads,
+            verbose: true,
+            ..PoolOptions::default()
+        })
+    }
+
+    /// Create a new ThreadPool that doesn't print what its workers are doing.
+    ///
+    /// # Panics
+    ///
+    /// Like `new`, this panics if number_of_threads is 0.
+    pub fn quiet(number_of_threads: usize) -> ThreadPool {
+        ThreadPool::with_options(PoolOptions {
+            threads: number_of_threads,
+            ..PoolOptions::default()
+        })
+    }
+
+    /// Create a ThreadPool set up by `options`, for example with a bounded queue or room to
+    /// grow.
+    ///
+    /// # Panics
+    ///
+    /// If there are no threads, `max_threads` is below `threads` or the capacity is 0.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::{Overflow, PoolOptions, ThreadPool};
+    ///
+    /// let pool = ThreadPool::with_options(PoolOptions {
+    ///     threads: 2,
+    ///     capacity: Some(100),
+    ///     overflow: Overflow::Reject,
+    ///     ..PoolOptions::default()
+    /// });
+    /// assert!(pool.try_execute(|| {}).is_ok());
+    /// ```
+    pub fn with_options(options: PoolOptions) -> ThreadPool {
+        assert!(options.threads > 0);
+        let max = options.max_threads.unwrap_or(options.threads);
+        assert!(max >= options.threads, "max_threads is below threads");
+        assert_ne!(Some(0), options.capacity, "the queue needs room for a job");
+        let shared = Arc::new(Shared {
+            queue: Mutex::new(Queue {
+                jobs: VecDeque::new(),
+                closed: false,
+                live: options.threads,
+                idle: 0,
+                retiring: 0,
+                watching: false,
+                min: options.threads,
+                max,
+            }),
+            available: Condvar::new(),
+            space: Condvar::new(),
+            capacity: options.capacity,
+            overflow: options.overflow,
+            grow_after: options.grow_after,
+            keep_alive: options.keep_alive,
+            workers: Mutex::new(Workers {
+                list: Vec::with_capacity(options.threads),
+                retired: JobCounts::default(),
+            }),
+            exit: Condvar::new(),
+            next_id: AtomicUsize::new(0),
+            verbose: options.verbose,
+        });
+        for _ in 0..options.threads {
+            shared.start().expect("failed to start a worker thread");
+        }
+        ThreadPool { shared }
+    }
+
+    /// Queue `f` to run on one of the workers. When the queue is full, what happens depends
+    /// on the pool's `Overflow`: this waits for room, drops `f` or drops the oldest job.
+    ///
+    /// # Panics
+    ///
+    /// If the pool is shutting down, which only a job running on it can still notice.
+    pub fn execute<F>(&self, f: F)
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = &self.shared;
+        let mut queue = lock(&shared.queue);
+        if shared.overflow == Overflow::Block {
+            while !queue.closed && shared.is_full(&queue) {
+                queue = shared
+                    .space
+                    .wait(queue)
+                    .unwrap_or_else(PoisonError::into_inner);
+            }
+        }
+        assert!(!queue.closed, "the thread pool is shutting down");
+        if shared.overflow == Overflow::Reject && shared.is_full(&queue) {
+            drop(queue);
+            if shared.verbose {
+                println!("The queue is full, rejected a job");
+            }
+            return;
+        }
+        // dropped after unlocking, like in `discard`
+        let _oldest = shared.push(queue, Box::new(f));
+    }
+
+    /// Queue `f` unless the queue is full, then hand it back. With `Overflow::DropOldest`
+    /// the oldest job makes room instead, so this always succeeds. Also hands `f` back if the
+    /// pool is shutting down.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::{PoolOptions, ThreadPool};
+    /// use std::sync::mpsc;
+    ///
+    /// let pool = ThreadPool::with_options(PoolOptions {
+    ///     threads: 1,
+    ///     capacity: Some(1),
+    ///     ..PoolOptions::default()
+    /// });
+    /// let (release, blocked) = mpsc::channel::<()>();
+    /// let (started, running) = mpsc::channel();
+    /// pool.execute(move || {
+    ///     started.send(()).unwrap();
+    ///     let _ = blocked.recv();
+    /// });
+    /// running.recv().unwrap();
+    /// assert!(pool.try_execute(|| {}).is_ok());
+    /// // the worker is busy and the queue is full
+    /// assert!(pool.try_execute(|| {}).is_err());
+    /// assert_eq!(1, pool.queued_len());
+    /// drop(release);
+    /// ```
+    pub fn try_execute<F>(&self, f: F) -> Result<(), F>
+    where
+        F: FnOnce() + Send + 'static,
+    {
+        let shared = &self.shared;
+        let queue = lock(&shared.queue);
+        if queue.closed || (shared.overflow != Overflow::DropOldest && shared.is_full(&queue)) {
+            return Err(f);
+        }
+        let _oldest = shared.push(queue, Box::new(f));
+        Ok(())
+    }
+
+    /// How many jobs are waiting for a worker.
+    pub fn queued_len(&self) -> usize {
+        lock(&self.shared.queue).jobs.len()
+    }
+
+    /// How many workers there are, not counting those that are about to retire.
+    pub fn threads(&self) -> usize {
+        lock(&self.shared.queue).staying()
+    }
+
+    /// Change the number of workers to `threads`. New workers start right away, surplus ones
+    /// quit once they are done with their current job. `threads` also becomes the fewest
+    /// workers the pool shrinks to, and the most it grows to if that was lower.
+    ///
+    /// # Panics
+    ///
+    /// If `threads` is 0, or if a worker thread can't be started.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::ThreadPool;
+    ///
+    /// let pool = ThreadPool::quiet(2);
+    /// pool.resize(6);
+    /// assert_eq!(6, pool.threads());
+    /// pool.resize(1);
+    /// assert_eq!(1, pool.threads());
+    /// ```
+    pub fn resize(&self, threads: usize) {
+        assert!(threads > 0);
+        let shared = &self.shared;
+        let mut queue = lock(&shared.queue);
+        if queue.closed {
+            return;
+        }
+        queue.min = threads;
+        queue.max = queue.max.max(threads);
+        let staying = queue.staying();
+        if threads < staying {
+            queue.retiring += staying - threads;
+            shared.available.notify_all();
+            return;
+        }
+        // call off retirements before starting new workers
+        let kept = queue.retiring.min(threads - staying);
+        queue.retiring -= kept;
+        let missing = threads - staying - kept;
+        queue.live += missing;
+        drop(queue);
+        for _ in 0..missing {
+            shared.start().expect("failed to start a worker thread");
+        }
+    }
+
+    /// Queue `f` like `execute`, and get a handle to wait for what it returns.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::ThreadPool;
+    ///
+    /// let pool = ThreadPool::quiet(2);
+    /// let answer = pool.spawn(|| 6 * 7);
+    /// let oops = pool.spawn(|| -> u32 { panic!("oops") });
+    /// assert_eq!(42, answer.join().unwrap());
+    /// assert_eq!(Some("oops"), oops.join().unwrap_err().message());
+    /// ```
+    pub fn spawn<F, R>(&self, f: F) -> JobHandle<R>
+    where
+        F: FnOnce() -> R + Send + 'static,
+        R: Send + 'static,
+    {
+        let (job, handle) = JobHandle::wrap(f);
+        self.execute(job);
+        handle
+    }
+
+    /// How many jobs have panicked so far.
+    pub fn failed_jobs(&self) -> usize {
+        let workers = lock(&self.shared.workers);
+        let failed: usize = workers
+            .list
+            .iter()
+            .map(|worker| worker.progress.failed.load(Ordering::SeqCst))
+            .sum();
+        workers.retired.failed + failed
+    }
+
+    /// Stop taking new jobs and wait up to `timeout` for the workers to finish.
+    ///
+    /// With `ShutdownMode::Drain` the queued jobs are run first, with `Discard` they are
+    /// dropped right away. Once the timeout is up the jobs that are still queued get dropped
+    /// and the workers that are still busy are left to finish their job on their own.
+    ///
+    /// # Examples
+    ///
+    /// ```
+    /// use rust_book::{ShutdownMode, ThreadPool};
+    /// use std::time::Duration;
+    ///
+    /// let mut pool = ThreadPool::quiet(2);
+    /// for _ in 0..8 {
+    ///     pool.execute(|| {});
+    /// }
+    /// let report = pool.shutdown(Duration::from_secs(10), ShutdownMode::Drain);
+    /// assert_eq!(8, report.completed());
+    /// assert_eq!((0, 0), (report.dropped, report.still_running()));
+    /// ```
+    pub fn shutdown(&mut self, timeout: Duration, mode: ShutdownMode) -> ShutdownReport {
+        let shared = &self.shared;
+        if shared.verbose {
+            println!("Shutting down the pool ({:?})", mode);
+        }
+        let mut dropped = 0;
+        {
+            let mut queue = lock(&shared.queue);
+            queue.closed = true;
+            shared.available.notify_all();
+            shared.space.notify_all();
+            if mode == ShutdownMode::Discard {
+                dropped += shared.discard(queue);
+            }
+        }
+
+        // without a deadline we wait as long as it takes
+        let deadline = Instant::now().checked_add(timeout);
+        let mut queue = lock(&shared.queue);
+        while queue.live > 0 {
+            match deadline {
+                Some(deadline) => {
+                    let now = Instant::now();
+                    if now >= deadline {
+                        break;
+                    }
+                    queue = shared
+                        .exit
+                        .wait_timeout(queue, deadline - now)
+                        .unwrap_or_else(PoisonError::into_inner)
+                        .0;
+                }
+                None => {
+                    queue = shared
+                        .exit
+                        .wait(queue)
+                        .unwrap_or_else(PoisonError::into_inner)
+                }
+            }
+        }
+        // the workers are on their way out, joining them won't take long
+        let finished = queue.live == 0;
+        // too late for the rest, the busy workers quit after their current job
+        dropped += shared.discard(queue);
+
+        let Workers { list, retired } = std::mem::take(&mut *lock(&shared.workers));
+        let mut workers = Vec::with_capacity(list.len());
+        for mut worker in list {
+            let running = worker.progress.running.load(Ordering::SeqCst);
+            if let Some(thread) = worker.thread.take() {
+                if finished || thread.is_finished() {
+                    if shared.verbose {
+                        println!("Shutting down Worker {}", worker.id);
+                    }
+                    // a worker that died has been replaced already
+                    let _ = thread.join();
+                }
+            }
+            workers.push(WorkerReport {
+                id: worker.id,
+                completed: worker.progress.completed.load(Ordering::SeqCst),
+                failed: worker.progress.failed.load(Ordering::SeqCst),
+                running,
+            });
+        }
+        ShutdownReport {
+            workers,
+            retired,
+            dropped,
+        }
+    }
+}
+
+impl Drop for ThreadPool {
+    fn drop(&mut self) {
+        // nothing left to do after an explicit shutdown
+        if !lock(&self.shared.queue).closed {
+            self.shutdown(Duration::MAX, ShutdownMode::Drain);
+        }
+    }
+}
+
+/// Waits for the result of a job queued with `ThreadPool::spawn`.
+///
+/// Dropping the handle doesn't cancel the job, the result just goes nowhere.
+#[derive(Debug)]
+pub struct JobHandle<R> {
+    receiver: mpsc::Receiver<thread::Result<R>>,
+}
+
+impl<R: Send + 'static> JobHandle<R> {
+    // A job that sends the result of `f` to the handle.
+    pub(crate) fn wrap<F>(f: F) -> (impl FnOnce() + Send + 'static, JobHandle<R>)
+    where
+        F: FnOnce() -> R + Send + 'static,
+    {
+        let (sender, receiver) = mpsc::channel();
+        let job = move || {
+            let result = panic::catch_unwind(AssertUnwindSafe(f));
+            let failed = result.is_err();
+            // nobody is waiting if the handle was dropped
+            let _ = sender.send(result);
+            // the payload went to the handle, but the worker should still count the failure
+            if failed {
+                panic::resume_unwind(Box::new("the job panicked"));
+            }
+        };
+        (job, JobHandle { receiver })
+    }
+}
+
+impl<R> JobHandle<R> {
+    /// Block until the job is done.
+    pub fn join(self) -> Result<R, JobError> {
+        match self.receiver.recv() {
+            Ok(result) => result.map_err(JobError::Panicked),
+            Err(_) => Err(JobError::Dropped),
+        }
+    }
+
+    /// The result if the job is done, otherwise the handle back to try again later.
+    pub fn try_join(self) -> Result<Result<R, JobError>, JobHandle<R>> {
+        match self.receiver.try_recv() {
+            Ok(result) => Ok(result.map_err(JobError::Panicked)),
+            Err(TryRecvError::Disconnected) => Ok(Err(JobError::Dropped)),
+            Err(TryRecvError::Empty) => Err(self),
+        }
+    }
+
+    /// Like `join`, but give up after `timeout` and hand the handle back.
+    pub fn join_timeout(self, timeout: Duration) -> Result<Result<R, JobError>, JobHandle<R>> {
+        match self.receiver.recv_timeout(timeout) {
+            Ok(result) => Ok(result.map_err(JobError::Panicked)),
+            Err(RecvTimeoutError::Disconnected) => Ok(Err(JobError::Dropped)),
+            Err(RecvTimeoutError::Timeout) => Err(self),
+        }
+    }
+}
+
+/// Why a job has no result.
+pub enum JobError {
+    /// The job panicked, with this payload.
+    Panicked(Box<dyn Any + Send + 'static>),
+    /// The job never ran, the pool was shut down before it got to it.
+    Dropped,
+}
+
+impl JobError {
+    /// The panic message, if the job panicked with a string like `panic!` does.
+    pub fn message(&self) -> Option<&str> {
+        match self {
+            JobError::Panicked(payload) => payload
+                .downcast_ref::<&str>()
+                .copied()
+                .or_else(|| payload.downcast_ref::<String>().map(String::as_str)),
+            JobError::Dropped => None,
+        }
+    }
+}
+
+impl fmt::Debug for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match self {
+            JobError::Panicked(_) => f.debug_tuple("Panicked").field(&self.message()).finish(),
+            JobError::Dropped => write!(f, "Dropped"),
+        }
+    }
+}
+
+impl fmt::Display for JobError {
+    fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+        match (self, self.message()) {
+            (JobError::Panicked(_), Some(message)) => write!(f, "the job panicked: {}", message),
+            (JobError::Panicked(_), None) => write!(f, "the job panicked"),
+            (JobError::Dropped, _) => write!(f, "the job was dropped before it ran"),
+        }
+    }
+}
+
+impl Error for JobError {}
+
+// Updated by the worker, read by `shutdown`.
+#[derive(Default)]
+struct Progress {
+    completed: AtomicUsize,
+    failed: AtomicUsize,
+    running: AtomicBool,
+}
+
+struct Worker {
+    id: usize,
+    thread: Option<JoinHandle<()>>,
+    progress: Arc<Progress>,
+}
+
+impl Worker {
+    // Start a worker with the next free id, ids are never reused. It has to be counted in
+    // `Queue::live` already.
+    fn spawn(shared: &Arc<Shared>) -> io::Result<Worker> {
+        let id = shared.next_id.fetch_add(1, Ordering::SeqCst);
+        let progress = Arc::new(Progress::default());
+        let counter = Arc::clone(&progress);
+        let shared = Arc::clone(shared);
+        let thread = thread::Builder::new().spawn(move || {
+            let verbose = shared.verbose;
+            let _sentinel = Sentinel {
+                shared: Arc::clone(&shared),
+                id,
+            };
+            let mut fresh = true;
+            while let Some(job) = shared.next_job(std::mem::take(&mut fresh)) {
+                if verbose {
+                    println!("Worker {} got job; executing.", id);
+                }
+                counter.running.store(true, Ordering::SeqCst);
+                run_job(job, |ok| {
+                    counter.running.store(false, Ordering::SeqCst);
+                    if ok {
+                        counter.completed.fetch_add(1, Ordering::SeqCst);
+                    } else {
+                        if verbose {
+                            println!("Worker {} job panicked", id);
+                        }
+                        counter.failed.fetch_add(1, Ordering::SeqCst);
+                    }
+                });
+            }
+            if verbose {
+                println!("Worker {} terminating...", id);
+            }
+            // retired, `shutdown` wants to hear from the others itself
+            if !lock(&shared.queue).closed {
+                shared.forget(id);
+            }
+        })?;
+        Ok(Worker {
+            id,
+            thread: Some(thread),
+            progress,
+        })
+    }
+}
+
+// Lives on the stack of a worker thread to notice when the thread dies. A worker that quits
+// the normal way has left `Queue::live` already.
+struct Sentinel {
+    shared: Arc<Shared>,
+    id: usize,
+}
+
+impl Drop for Sentinel {
+    fn drop(&mut self) {
+        if thread::panicking() {
+            self.shared.replace(self.id);
+        }
+    }
+}
+
+impl Shared {
+    // Drop the queued jobs, after letting go of the lock: what a job owns might want to
+    // queue another job when it gets dropped.
+    fn discard(&self, mut queue: MutexGuard<Queue>) -> usize {
+        let jobs = std::mem::take(&mut queue.jobs);
+        self.space.notify_all();
+        drop(queue);
+        jobs.len()
+    }
+
+    fn is_full(&self, queue: &Queue) -> bool {
+        self.capacity
+            .is_some_and(|capacity| queue.jobs.len() >= capacity)
+    }
+
+    // Queue a job on a queue that has room, unless the oldest job has to go. That one is
+    // returned to be dropped by the caller after unlocking.
+    fn push(self: &Arc<Self>, mut queue: MutexGuard<Queue>, job: Job) -> Option<Job> {
+        let oldest = if self.is_full(&queue) {
+            queue.jobs.pop_front().map(|(_, job)| job)
+        } else {
+            None
+        };
+        queue.jobs.push_back((Instant::now(), job));
+        self.available.notify_one();
+        self.grow(queue);
+        if oldest.is_some() && self.verbose {
+            println!("The queue is full, dropped the oldest job");
+        }
+        oldest
+    }
+
+    // Wait for the next job. None once the worker should quit: the pool is closed and the
+    // queue is empty, the pool was resized, or there was nothing to do for `keep_alive`. A
+    // `fresh` worker takes a waiting job before it retires, the pool might have grown for that
+    // job while the retiring workers were still busy.
+    fn next_job(self: &Arc<Self>, fresh: bool) -> Option<Job> {
+        let mut queue = lock(&self.queue);
+        loop {
+            if queue.retiring > 0 && (!fresh || queue.jobs.is_empty()) {
+                queue.retiring -= 1;
+                return self.quit(queue);
+            }
+            if let Some((_, job)) = queue.jobs.pop_front() {
+                self.space.notify_one();
+                // the next job might have waited for long enough already
+                self.grow(queue);
+                return Some(job);
+            }
+            if queue.closed {
+                return self.quit(queue);
+            }
+            queue.idle += 1;
+            let (mut guard, wait) = self
+                .available
+                .wait_timeout(queue, self.keep_alive)
+                .unwrap_or_else(PoisonError::into_inner);
+            guard.idle -= 1;
+            queue = guard;
+            if wait.timed_out() && queue.jobs.is_empty() && queue.staying() > queue.min {
+                return self.quit(queue);
+            }
+        }
+    }
+
+    // Start another worker if the oldest job has been waiting for too long and all workers
+    // are busy, as far as `max` allows. If it hasn't waited long enough yet, a watcher checks
+    // again later: the busy workers might not come back for a while, and no new job might
+    // come in to check then.
+    fn grow(self: &Arc<Self>, mut queue: MutexGuard<Queue>) {
+        let waited = match queue.jobs.front() {
+            Some((queued, _)) => queued.elapsed(),
+            None => return,
+        };
+        if queue.closed || queue.idle > 0 || queue.staying() >= queue.max {
+            return;
+        }
+        if waited < self.grow_after {
+            if !queue.watching {
+                queue.watching = true;
+                drop(queue);
+                self.start_watcher();
+            }
+            return;
+        }
+        queue.live += 1;
+        drop(queue);
+        if self.verbose {
+            println!("A job has waited for {:?}, adding a worker", waited);
+        }
+        // if there is no thread to spare the workers we have keep going
+        let _ = self.start();
+    }
+
+    // Start a thread that runs `watch`, it is counted in `Queue::watching` already.
+    fn start_watcher(self: &Arc<Self>) {
+        let shared = Arc::clone(self);
+        if thread::Builder::new()
+            .spawn(move || shared.watch())
+            .is_err()
+        {
+            // then only the jobs coming and going make the pool grow
+            lock(&self.queue).watching = false;
+        }
+    }
+
+    // Wait until the oldest job has waited for `grow_after` and try to grow the pool, for as
+    // long as jobs are waiting and there is room to grow.
+    fn watch(self: &Arc<Self>) {
+        let mut queue = lock(&self.queue);
+        while let Some(queued) = queue.jobs.front().map(|(queued, _)| *queued) {
+            if queue.closed || queue.staying() >= queue.max {
+                break;
+            }
+            let mut wait = self.grow_after.saturating_sub(queued.elapsed());
+            if wait.is_zero() {
+                self.grow(queue);
+                // worked or not, the next look is once the new worker had time to take a job
+                wait = self.grow_after;
+            } else {
+                drop(queue);
+            }
+            thread::sleep(wait);
+            queue = lock(&self.queue);
+        }
+        queue.watching = false;
+    }
+
+    // Start a worker that is counted in `Queue::live` already. Returns the id of the new
+    // worker.
+    fn start(self: &Arc<Self>) -> io::Result<usize> {
+        // registered before it can take a job, or `shutdown` could miss it
+        let mut workers = lock(&self.workers);
+        match Worker::spawn(self) {
+            Ok(worker) => {
+                let id = worker.id;
+                workers.list.push(worker);
+                Ok(id)
+            }
+            Err(e) => {
+                drop(workers);
+                self.quit(lock(&self.queue));
+                Err(e)
+            }
+        }
+    }
+
+    // A worker thread died, start another one in its place while there is work left.
+    fn replace(self: &Arc<Self>, id: usize) {
+        let queue = lock(&self.queue);
+        if queue.closed && queue.jobs.is_empty() {
+            self.quit(queue);
+            return;
+        }
+        drop(queue);
+        self.forget(id);
+        // we are unwinding, so not being able to start a thread can't be a panic
+        if let Ok(new) = self.start() {
+            if self.verbose {
+                println!("Worker {} died, Worker {} takes over", id, new);
+            }
+        }
+    }
+
+    // Let go of a worker whose thread is about to end, and add what it did to the totals.
+    // Nothing to do if `shutdown` took it already.
+    fn forget(&self, id: usize) {
+        let mut workers = lock(&self.workers);
+        if let Some(i) = workers.list.iter().position(|worker| worker.id == id) {
+            // dropping the handle detaches the thread, which is done anyway
+            let worker = workers.list.remove(i);
+            workers.retired.completed += worker.progress.completed.load(Ordering::SeqCst);
+            workers.retired.failed += worker.progress.failed.load(Ordering::SeqCst);
+        }
+    }
+
+    // Count a worker out. Always None, for `next_job` to return.
+    fn quit(&self, mut queue: MutexGuard<Queue>) -> Option<Job> {
+        queue.live -= 1;
+        self.exit.notify_all();
+        None
+    }
+}
+
+// Our locks are never held while a job runs and we don't panic while holding them, so the
+// data behind a poisoned lock is still fine, only some thread died at the wrong moment.
+pub(crate) fn lock<T>(mutex: &Mutex<T>) -> MutexGuard<'_, T> {
+    mutex.lock().unwrap_or_else(PoisonError::into_inner)
+}
+
+// Run a job on a worker and tell `finished` whether it went through. Dropping the payload
+// of a panic can panic again, so that comes last: the second panic ends the worker thread,
+// and its sentinel starts another one.
+pub(crate) fn run_job(job: Job, finished: impl FnOnce(bool)) {
+    let result = panic::catch_unwind(AssertUnwindSafe(|| job.call_box()));
+    finished(result.is_ok());
+    drop(result);
+}
+
+// A panic payload that panics again when it's dropped, which kills the worker.
+#[cfg(test)]
+pub(crate) struct Bomb;
+
+#[cfg(test)]
+impl Drop for Bomb {
+    fn drop(&mut self) {
+        panic!("boom");
+    }
+}
+
+#[cfg(test)]
+mod tests {
+    use super::*;
+
+    // A job that blocks until the returned sender is dropped or sends.
+    fn blocked(pool: &ThreadPool) -> mpsc::Sender<()> {
+        let (sender, receiver) = mpsc::channel::<()>();
+        pool.execute(move || {
+            let _ = receiver.recv();
+        });
+        sender
+    }
+
+    // Let a blocked job go once the pool is closed, so nothing else can start before.
+    fn release_on_shutdown(pool: &ThreadPool, release: mpsc::Sender<()>) {
+        let shared = Arc::clone(&pool.shared);
+        thread::spawn(move || {
+            while !shared.queue.lock().unwrap().closed {
+                thread::yield_now();
+            }
+            drop(release);
+        });
+    }
+
+    #[test]
+    fn drain_runs_the_queued_jobs() {
+        let mut pool = ThreadPool::quiet(2);
+        let done = Arc::new(AtomicUsize::new(0));
+        for _ in 0..20 {
+            let done = Arc::clone(&done);
+            pool.execute(move || {
+                done.fetch_add(1, Ordering::SeqCst);
+            });
+        }
+        let report = pool.shutdown(Duration::from_secs(10), ShutdownMode::Drain);
+        assert_eq!(20, done.load(Ordering::SeqCst));
+        assert_eq!(20, report.completed());
+        assert_eq!(
+            vec![0, 1],
+            report.workers.iter().map(|w| w.id).collect::<Vec<_>>()
+        );
+        assert_eq!(0, report.dropped);
+    }
+
+    #[test]
+    fn discard_drops_the_queued_jobs() {
+        let mut pool = ThreadPool::quiet(1);
+        let release = blocked(&pool);
+        for _ in 0..5 {
+            pool.execute(|| panic!("a discarded job ran"));
+        }
+        // let the worker pick up the blocking job first
+        while !lock(&pool.shared.workers).list[0]
+            .progress
+            .running
+            .load(Ordering::SeqCst)
+        {
+            thread::yield_now();
+        }
+        release_on_shutdown(&pool, release);
+        let report = pool.shutdown(Duration::from_secs(10), ShutdownMode::Discard);
+        assert_eq!(
+            (1, 5, 0),
+            (report.completed(), report.dropped, report.still_running())
+        );
+    }
+
+    #[test]
+    fn timeout_reports_busy_workers() {
+        let mut pool = ThreadPool::quiet(2);
+        let release = blocked(&pool);
+        let (sender, receiver) = mpsc::channel();
+        pool.execute(move || sender.send(()).unwrap());
+        receiver.recv().unwrap();
+        let report = pool.shutdown(Duration::from_millis(50), ShutdownMode::Drain);
+        assert_eq!(1, report.still_running());
+        assert_eq!(0, report.dropped);
+        // the busy worker is on its own now and quits once its job is done
+        drop(release);
+    }
+
+    #[test]
+    fn results_come_back() {
+        let pool = ThreadPool::quiet(2);
+        let handles: Vec<_> = (0..10).map(|i| pool.spawn(move || i * i)).collect();
+        let squares: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(vec![0, 1, 4, 9, 16, 25, 36, 49, 64, 81], squares);
+
+        let error = pool
+            .spawn(|| -> () { panic!("job {} failed", 7) })
+            .join()
+            .unwrap_err();
+        assert_eq!("the job panicked: job 7 failed", error.to_string());
+        assert!(matches!(error, JobError::Panicked(_)));
+    }
+
+    #[test]
+    fn waiting_without_blocking() {
+        let pool = ThreadPool::quiet(1);
+        let (release, receiver) = mpsc::channel::<()>();
+        let handle = pool.spawn(move || receiver.recv().is_err());
+        let handle = handle.try_join().unwrap_err();
+        let handle = handle.join_timeout(Duration::from_millis(10)).unwrap_err();
+        drop(release);
+        let result = handle.join_timeout(Duration::from_secs(10)).unwrap();
+        assert!(result.unwrap());
+    }
+
+    #[test]
+    fn dropped_jobs_have_no_result() {
+        let mut pool = ThreadPool::quiet(1);
+        let release = blocked(&pool);
+        let handle = pool.spawn(|| 1);
+        release_on_shutdown(&pool, release);
+        pool.shutdown(Duration::from_secs(10), ShutdownMode::Discard);
+        assert!(matches!(handle.join(), Err(JobError::Dropped)));
+    }
+
+    #[test]
+    fn panics_stay_in_their_job() {
+        // one worker runs the jobs in order, so a result means the jobs before it are done
+        let mut pool = ThreadPool::quiet(1);
+        for i in 0..10 {
+            pool.execute(move || panic!("job {} failed", i));
+        }
+        assert!(pool.spawn(|| panic!("one more")).join().is_err());
+        let handles: Vec<_> = (0..4).map(|i| pool.spawn(move || i)).collect();
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().unwrap()).collect();
+        assert_eq!(vec![0, 1, 2, 3], results);
+        assert_eq!(11, pool.failed_jobs());
+        let report = pool.shutdown(Duration::from_secs(10), ShutdownMode::Drain);
+        assert_eq!((4, 11), (report.completed(), report.failed()));
+        // still the same worker
+        assert_eq!(1, report.workers.len());
+    }
+
+    #[test]
+    fn dead_workers_are_replaced() {
+        let mut pool = ThreadPool::quiet(1);
+        pool.execute(|| panic::panic_any(Bomb));
+        assert_eq!(5, pool.spawn(|| 5).join().unwrap());
+        let report = pool.shutdown(Duration::from_secs(10), ShutdownMode::Drain);
+        // the new worker got a new id, the dead one is only counted
+        let ids: Vec<_> = report.workers.iter().map(|w| w.id).collect();
+        assert_eq!(vec![1], ids);
+        assert_eq!(
+            JobCounts {
+                completed: 0,
+                failed: 1
+            },
+            report.retired
+        );
+        assert_eq!((1, 1), (report.completed(), report.failed()));
+    }
+
+    #[test]
+    fn poisoned_locks_are_recovered() {
+        let pool = ThreadPool::quiet(2);
+        let shared = Arc::clone(&pool.shared);
+        let poisoner = thread::spawn(move || {
+            let _queue = shared.queue.lock().unwrap();
+            panic!("poisoning the queue");
+        });
+        assert!(poisoner.join().is_err());
+        assert!(pool.shared.queue.is_poisoned());
+        assert_eq!("still works", pool.spawn(|| "still works").join().unwrap());
+    }
+
+    // A pool with one worker that is stuck on a job until the sender goes away.
+    fn busy(capacity: usize, overflow: Overflow) -> (ThreadPool, mpsc::Sender<()>) {
+        let pool = ThreadPool::with_options(PoolOptions {
+            threads: 1,
+            capacity: Some(capacity),
+            overflow,
+            ..PoolOptions::default()
+        });
+        let release = blocked(&pool);
+        while pool.queued_len() > 0 {
+            thread::yield_now();
+        }
+        (pool, release)
+    }
+
+    #[test]
+    fn full_queue_blocks() {
+        let (pool, release) = busy(1, Overflow::Block);
+        pool.execute(|| {});
+        assert!(pool.try_execute(|| {}).is_err());
+        let pool = Arc::new(pool);
+        let queued = Arc::new(AtomicBool::new(false));
+        let waiting = {
+            let (pool, queued) = (Arc::clone(&pool), Arc::clone(&queued));
+            thread::spawn(move || {
+                pool.execute(|| {});
+                queued.store(true, Ordering::SeqCst);
+            })
+        };
+        thread::sleep(Duration::from_millis(50));
+        assert!(!queued.load(Ordering::SeqCst));
+        assert_eq!(1, pool.queued_len());
+        drop(release);
+        waiting.join().unwrap();
+        assert!(queued.load(Ordering::SeqCst));
+    }
+
+    #[test]
+    fn full_queue_rejects() {
+        let (pool, release) = busy(2, Overflow::Reject);
+        assert!(pool.try_execute(|| {}).is_ok());
+        let kept = pool.spawn(|| "kept");
+        let ran = Arc::new(AtomicBool::new(false));
+        let flag = Arc::clone(&ran);
+        // the same job comes back
+        let job = pool
+            .try_execute(move || flag.store(true, Ordering::SeqCst))
+            .unwrap_err();
+        job();
+        assert!(ran.load(Ordering::SeqCst));
+        let rejected = pool.spawn(|| "rejected");
+        assert_eq!(2, pool.queued_len());
+        drop(release);
+        assert_eq!("kept", kept.join().unwrap());
+        assert!(matches!(rejected.join(), Err(JobError::Dropped)));
+    }
+
+    #[test]
+    fn full_queue_drops_the_oldest() {
+        let (pool, release) = busy(2, Overflow::DropOldest);
+        let handles: Vec<_> = (0..4).map(|i| pool.spawn(move || i)).collect();
+        assert!(pool.try_execute(|| {}).is_ok());
+        assert_eq!(2, pool.queued_len());
+        drop(release);
+        let results: Vec<_> = handles.into_iter().map(|h| h.join().ok()).collect();
+        assert_eq!(vec![None, None, None, Some(3)], results);
+    }
+
+    // Poll until `done` or give up after a while.
+    fn eventually(done: impl Fn() -> bool) -> bool {
+        let deadline = Instant::now() + Duration::from_secs(10);
+        while !done() {
+            if Instant::now() > deadline {
+                return false;
+            }
+            thread::sleep(Duration::from_millis(5));
+        }
+        true
+    }
+
+    #[test]
+    fn grows_while_jobs_wait_and_shrinks_when_idle() {
+        let pool = ThreadPool::with_options(PoolOptions {
+            threads: 1,
+            max_threads: Some(4),
+            grow_after: Duration::from_millis(10),
+            keep_alive: Duration::from_millis(100),
+            ..PoolOptions::default()
+        });
+        // all at once, so only the waiting makes the pool grow
+        let releases: Vec<_> = (0..4).map(|_| blocked(&pool)).collect();
+        assert!(eventually(|| pool.threads() == 4));
+        assert!(eventually(|| pool.queued_len() == 0));
+        // no fifth worker for nothing
+        thread::sleep(Duration::from_millis(50));
+        assert_eq!(4, pool.threads());
+        drop(releases);
+        assert!(eventually(|| pool.threads() == 1));
+        assert_eq!(1, pool.spawn(|| 1).join().unwrap());
+    }
+
+    #[test]
+    fn resized_at_runtime() {
+        let mut pool = ThreadPool::quiet(2);
+        pool.resize(4);
+        assert_eq!(4, pool.threads());
+        pool.resize(1);
+        assert_eq!(1, pool.threads());
+        assert!(eventually(|| lock(&pool.shared.queue).live == 1));
+        // the retired ones are let go of
+        assert!(eventually(|| lock(&pool.shared.workers).list.len() == 1));
+        // the retired workers' ids aren't handed out again
+        pool.resize(3);
+        let results: Vec<_> = (0..6).map(|i| pool.spawn(move || i)).collect();
+        assert!(results.into_iter().all(|handle| handle.join().is_ok()));
+        let report = pool.shutdown(Duration::from_secs(10), ShutdownMode::Drain);
+        let ids: Vec<_> = report.workers.iter().map(|w| w.id).collect();
+        assert_eq!(3, ids.len());
+        assert!(ids[0] < 4);
+        assert_eq!([4, 5], ids[1..]);
+        assert_eq!(6, report.completed());
+    }
+
+    #[test]
+    fn grows_past_the_workers_that_retire() {
+        let pool = ThreadPool::with_options(PoolOptions {
+            threads: 4,
+            max_threads: Some(4),
+            grow_after: Duration::from_millis(10),
+            ..PoolOptions::default()
+        });
+        let releases: Vec<_> = (0..4).map(|_| blocked(&pool)).collect();
+        assert!(eventually(|| lock(&pool.shared.queue).idle == 0));
+        // the three that go are still busy, the burst gets new workers
+        pool.resize(1);
+        let burst: Vec<_> = (0..3)
+            .map(|_| pool.spawn(|| thread::sleep(Duration::from_millis(200))))
+            .collect();
+        for handle in burst {
+            let done = handle.join_timeout(Duration::from_secs(10));
+            assert!(matches!(done, Ok(Ok(()))));
+        }
+        assert_eq!(4, pool.threads());
+        drop(releases);
+        assert!(eventually(|| lock(&pool.shared.queue).live == 4));
+        assert_eq!(1, pool.spawn(|| 1).join().unwrap());
+    }
+}